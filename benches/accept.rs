@@ -0,0 +1,98 @@
+//! Accepts per second under connection churn, run with `cargo bench --bench accept`.
+//!
+//! Every iteration opens a wave of short-lived connections that each send one message and
+//! close, the workload LISTEN_BACKLOG and ACCEPT_TASKS are for. The listener comes from
+//! `bind_listener` like the server's, and the accept loops read each connection to the end
+//! the way a raw one-message connection is read. Criterion reports connections per second.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use scooper::bind_listener;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::runtime::{Builder, Runtime};
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
+
+const CONNECTIONS: usize = 500; // Opened all at once in every iteration
+const BACKLOGS: [u32; 3] = [128, 1024, 4096];
+const ACCEPT_TASKS: [usize; 2] = [1, 4];
+
+async fn accept_loop(listener: Arc<TcpListener>, done: mpsc::UnboundedSender<()>) {
+    while let Ok((mut socket, _)) = listener.accept().await {
+        let done = done.clone();
+        tokio::spawn(async move {
+            let mut message = Vec::new();
+            let _ = socket.read_to_end(&mut message).await;
+            let _ = done.send(());
+        });
+    }
+}
+
+/// Opens `CONNECTIONS` connections at once and waits until the server side has read all the
+/// ones that connected.
+async fn churn(addr: SocketAddr, done: &mut mpsc::UnboundedReceiver<()>) -> Duration {
+    let started = Instant::now();
+    let mut clients = JoinSet::new();
+    for _ in 0..CONNECTIONS {
+        clients.spawn(async move {
+            let mut socket = TcpStream::connect(addr).await?;
+            socket.write_all(b"one short message").await?;
+            socket.shutdown().await
+        });
+    }
+    let mut connected = 0;
+    while let Some(client) = clients.join_next().await {
+        connected += usize::from(matches!(client, Ok(Ok(()))));
+    }
+    for _ in 0..connected {
+        done.recv().await;
+    }
+    started.elapsed()
+}
+
+fn bench(c: &mut Criterion, runtime: &Runtime, backlog: u32, accept_tasks: usize) {
+    let (tx, mut done) = mpsc::unbounded_channel();
+    let (addr, mut accept_loops) = runtime.block_on(async {
+        let listener = bind_listener("127.0.0.1:0".parse().unwrap(), backlog).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let listener = Arc::new(listener);
+        let mut accept_loops = JoinSet::new();
+        for _ in 0..accept_tasks {
+            accept_loops.spawn(accept_loop(Arc::clone(&listener), tx.clone()));
+        }
+        (addr, accept_loops)
+    });
+    let mut group = c.benchmark_group(format!("{accept_tasks} accept tasks"));
+    group.throughput(Throughput::Elements(CONNECTIONS as u64));
+    // A backlog that overflows costs a SYN retry of a second, so samples can be slow
+    group.sample_size(10);
+    group.bench_function(BenchmarkId::new("backlog", backlog), |b| {
+        b.iter_custom(|iters| {
+            runtime.block_on(async {
+                let mut elapsed = Duration::ZERO;
+                for _ in 0..iters {
+                    elapsed += churn(addr, &mut done).await;
+                }
+                elapsed
+            })
+        });
+    });
+    group.finish();
+    runtime.block_on(accept_loops.shutdown());
+}
+
+fn accepts(c: &mut Criterion) {
+    let runtime = Builder::new_multi_thread().enable_all().build().unwrap();
+    for accept_tasks in ACCEPT_TASKS {
+        for backlog in BACKLOGS {
+            bench(c, &runtime, backlog, accept_tasks);
+        }
+    }
+}
+
+criterion_group!(benches, accepts);
+criterion_main!(benches);
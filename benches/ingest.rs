@@ -0,0 +1,124 @@
+//! Throughput of the logging engine without sockets, run with `cargo bench`.
+//!
+//! Every case logs synthetic messages the way a connection does, through `Message::new` and
+//! `ingest`, into a sink that keeps its last batch in memory. Criterion reports the payload
+//! bytes per second and compares them with the previous run, so regressions show up as lower
+//! numbers.
+
+use std::future::Future;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use scooper::{
+    ingest, BatchedLog, DedupCache, EntryOptions, EntrySink, LogFormat, Message, Metrics,
+    RecordSeparator, SanitizeMode, StampMode,
+};
+use tokio::runtime::Builder;
+
+const MESSAGE_SIZES: [usize; 4] = [16, 256, 4096, 65536];
+const CLIENT: &str = "192.0.2.1:40000";
+
+/// Keeps only the last batch, so a long run doesn't grow without bounds.
+#[derive(Default)]
+struct MemorySink(Vec<u8>);
+
+impl EntrySink for MemorySink {
+    fn write_entries(
+        &mut self,
+        data: &[u8],
+        _received: &[Instant],
+        _payload_bytes: usize,
+    ) -> impl Future<Output = io::Result<()>> + Send {
+        self.0.clear();
+        self.0.extend_from_slice(data);
+        std::future::ready(Ok(()))
+    }
+}
+
+/// Text with the occasional control character, so sanitizing has something to do.
+fn message(size: usize) -> Vec<u8> {
+    (0..size)
+        .map(|i| match i % 97 {
+            0 => b'\t',
+            n => b' ' + (n % 94) as u8,
+        })
+        .collect()
+}
+
+fn bench(
+    c: &mut Criterion,
+    name: &str,
+    options: &EntryOptions,
+    dedup: Option<&Mutex<DedupCache>>,
+    buffer_bytes: usize,
+) {
+    let runtime = Builder::new_current_thread().build().unwrap();
+    let mut group = c.benchmark_group(name);
+    for size in MESSAGE_SIZES {
+        let payload = message(size);
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(size), &payload, |b, payload| {
+            let sink = Arc::new(tokio::sync::Mutex::new(MemorySink::default()));
+            let mut log = BatchedLog {
+                buffer_bytes,
+                ..BatchedLog::new(sink, Arc::new(Metrics::default()))
+            };
+            b.iter_custom(|iters| {
+                runtime.block_on(async {
+                    let started = Instant::now();
+                    for _ in 0..iters {
+                        let message = Message::new(payload, dedup, options);
+                        ingest(&mut log, CLIENT, &message, dedup, options, Instant::now())
+                            .await
+                            .unwrap();
+                    }
+                    started.elapsed()
+                })
+            });
+        });
+    }
+    group.finish();
+}
+
+fn engine(c: &mut Criterion) {
+    bench(c, "stamped", &EntryOptions::default(), None, 0);
+    bench(
+        c,
+        "stamped, batched",
+        &EntryOptions::default(),
+        None,
+        64 << 10,
+    );
+    let tagged = EntryOptions {
+        human_size: true,
+        tag_content: true,
+        ..Default::default()
+    };
+    bench(c, "stamped, tagged", &tagged, None, 0);
+    let sanitized = EntryOptions {
+        sanitize: SanitizeMode::Escape,
+        ..Default::default()
+    };
+    bench(c, "stamped, sanitized", &sanitized, None, 0);
+    // The same payload over and over, so all but the first are duplicates
+    let dedup = Mutex::new(DedupCache::new(1024, 64 << 20));
+    bench(
+        c,
+        "stamped, dedup",
+        &EntryOptions::default(),
+        Some(&dedup),
+        0,
+    );
+    for format in [LogFormat::Json, LogFormat::Binary] {
+        let options = EntryOptions {
+            formatter: format.formatter(StampMode::Full, RecordSeparator::Leading, b""),
+            ..Default::default()
+        };
+        bench(c, format.as_str(), &options, None, 0);
+    }
+}
+
+criterion_group!(benches, engine);
+criterion_main!(benches);
@@ -0,0 +1,585 @@
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::fs;
+use std::io::{self, Seek, SeekFrom, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::path::{Path, PathBuf};
+use std::thread::sleep;
+use std::time::Duration;
+
+use scooper::config::{ConfigSource, ServerConfig, DEFAULT_DEDUP_WINDOW_BYTES};
+use scooper::log_reader::{
+    compression_of, is_finished_compressed, open_log, summarize_log, verify_log, DuplicateResolver,
+    LogEntry, LogReader, MergedReader, ReadError,
+};
+use scooper::{
+    format_timestamp, human_readable_size, json_string, parse_timestamp, sanitize_payload,
+    CompressAlgo, SanitizeMode,
+};
+
+// Concurrent connections can stamp entries slightly out of order, so a search only stops
+// once it's this far past `--to`
+const SEARCH_EARLY_EXIT_SLACK_MS: u128 = 1000;
+const FOLLOW_POLL_INTERVAL: Duration = Duration::from_millis(250);
+const REPLAY_MAX_RETRIES: u32 = 5;
+const REPLAY_INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+const REPLAY_MAX_BACKOFF: Duration = Duration::from_secs(5);
+
+const USAGE: &str = "Usage:
+  scooper [--config <file.toml>] [--<setting> <value>...]
+                                 Run the server, settings are read from the config file,
+                                 then environment variables, then flags (highest precedence)
+  scooper --check-config [--<setting> <value>...]
+                                 Validate the settings and print them without starting the
+                                 server, exits with 2 if they're invalid
+  scooper read <file>... [--follow]
+                                 Print the entries of log files (merged by time, e.g. for
+                                 shards), --follow keeps watching a file like `tail -f`
+  scooper search <file> [--from <time>] [--to <time>] [--client <ip>] [--json]
+                                 Print the entries in a time range (millis or ISO8601)
+  scooper count <file> [--from <time>] [--to <time>] [--client <ip>]
+                                 Count the messages that match, and their total size
+  scooper replay <file> --to <addr> [--realtime]
+                                 Re-send the logged payloads to a server, one connection each,
+                                 --realtime keeps the original timing between messages
+  scooper verify <file>          Check the structural integrity of a log file
+  scooper list <dir> [--sort name|size]
+                                 List the log files of LOG_FILE in a directory
+
+Exit codes of the server:
+  0  Clean shutdown (Ctrl+C)
+  1  Other errors, e.g. the log is full (ON_FULL=exit or drain) or an I/O error
+  2  Invalid configuration
+  3  A listen address can't be bound
+  4  The disk is full
+  5  ON_FULL=drain gave up waiting for open connections
+
+Signals of the server (Unix):
+  SIGUSR1  Print a status summary
+  SIGHUP   Reload SANITIZE, CONTENT_TAG, STAMP_HUMAN_SIZE, INSTANCE_TAG, SEPARATOR and
+           LOG_FORMAT from the config file and flags, other changes need a restart";
+
+struct Args {
+    positional: Vec<String>,
+    flags: HashMap<String, String>,
+}
+
+impl Args {
+    /// Splits `--flag value` / `--flag=value` pairs from positional arguments.
+    /// Flags listed in `switches` don't take a value.
+    fn parse(args: &[String], switches: &[&str]) -> Self {
+        let mut positional = Vec::new();
+        let mut flags = HashMap::new();
+        let mut iter = args.iter();
+        while let Some(arg) = iter.next() {
+            let Some(flag) = arg.strip_prefix("--") else {
+                positional.push(arg.clone());
+                continue;
+            };
+            match flag.split_once('=') {
+                Some((name, value)) => flags.insert(name.to_string(), value.to_string()),
+                None if switches.contains(&flag) => flags.insert(flag.to_string(), String::new()),
+                None => flags.insert(flag.to_string(), iter.next().cloned().unwrap_or_default()),
+            };
+        }
+        Self { positional, flags }
+    }
+
+    fn flag(&self, name: &str) -> Option<&str> {
+        self.flags.get(name).map(String::as_str)
+    }
+}
+
+fn usage_error(message: &str) -> io::Result<i32> {
+    eprintln!("{message}\n\n{USAGE}");
+    Ok(2)
+}
+
+/// Runs a subcommand and returns the process exit code.
+pub fn run(command: &str, args: &[String]) -> io::Result<i32> {
+    match command {
+        "read" => read(&Args::parse(args, &["follow"])),
+        "search" => search(&Args::parse(args, &["json"])),
+        "count" => count(&Args::parse(args, &[])),
+        "replay" => replay(&Args::parse(args, &["realtime"])),
+        "verify" => verify(&Args::parse(args, &[])),
+        "list" => list(&Args::parse(args, &[])),
+        "help" | "--help" | "-h" => {
+            println!("{USAGE}");
+            Ok(0)
+        }
+        other => usage_error(&format!("Unknown command: {other}")),
+    }
+}
+
+fn render_entry(entry: &LogEntry) -> String {
+    if let Some(kind) = entry.run_marker() {
+        return format!(
+            "[{}] --- server {kind} ---\n",
+            format_timestamp(entry.timestamp)
+        );
+    }
+    if let Some(seq) = entry.duplicate_of() {
+        return format!(
+            "[{}] {} (duplicate of #{seq})\n",
+            format_timestamp(entry.timestamp),
+            entry.client
+        );
+    }
+    let kind = if entry.is_text() { "text" } else { "bin" };
+    let instance = entry
+        .instance()
+        .map(|instance| format!(", instance {instance}"))
+        .unwrap_or_default();
+    let mut out = format!(
+        "[{}] {} ({}, {kind}{instance})\n",
+        format_timestamp(entry.timestamp),
+        entry.client,
+        human_readable_size(entry.len)
+    );
+    if entry.is_text() {
+        let payload = sanitize_payload(&entry.payload, SanitizeMode::Escape, "");
+        out.push_str(&String::from_utf8_lossy(&payload));
+    } else {
+        for (i, chunk) in entry.payload.chunks(16).enumerate() {
+            let hex: Vec<String> = chunk.iter().map(|b| format!("{b:02x}")).collect();
+            let ascii: String = chunk
+                .iter()
+                .map(|&b| {
+                    if b.is_ascii_graphic() || b == b' ' {
+                        b as char
+                    } else {
+                        '.'
+                    }
+                })
+                .collect();
+            let _ = writeln!(out, "{:08x}  {:<47}  |{ascii}|", i * 16, hex.join(" "));
+        }
+    }
+    if !out.ends_with('\n') {
+        out.push('\n');
+    }
+    out
+}
+
+fn render_entry_json(entry: &LogEntry) -> String {
+    let payload = match std::str::from_utf8(&entry.payload) {
+        Ok(text) if entry.is_text() => format!("\"payload\":{}", json_string(text)),
+        _ => {
+            let hex: String = entry.payload.iter().map(|b| format!("{b:02x}")).collect();
+            format!("\"payload_hex\":\"{hex}\"")
+        }
+    };
+    let instance = entry
+        .instance()
+        .map(|instance| format!("\"instance\":{},", json_string(instance)))
+        .unwrap_or_default();
+    format!(
+        "{{\"timestamp\":{},\"time\":\"{}\",\"client\":{},{instance}\"len\":{},\"text\":{},{payload}}}\n",
+        entry.timestamp,
+        format_timestamp(entry.timestamp),
+        json_string(&entry.client),
+        entry.len,
+        entry.is_text()
+    )
+}
+
+/// Matches a client filter against the full client field, or just its IP.
+fn client_matches(client: &str, filter: &str) -> bool {
+    client == filter
+        || client
+            .parse::<SocketAddr>()
+            .is_ok_and(|addr| addr.ip().to_string() == filter)
+}
+
+#[cfg(unix)]
+fn file_id(metadata: &fs::Metadata) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    Some(metadata.ino())
+}
+
+#[cfg(not(unix))]
+fn file_id(_metadata: &fs::Metadata) -> Option<u64> {
+    None
+}
+
+fn read(args: &Args) -> io::Result<i32> {
+    let paths = &args.positional;
+    if paths.is_empty() {
+        return usage_error("Missing file to read");
+    }
+    if args.flag("follow").is_some() {
+        if paths.len() > 1 {
+            return usage_error("--follow only supports a single file");
+        }
+        let path = Path::new(&paths[0]);
+        if compression_of(path)? != CompressAlgo::None {
+            return usage_error("--follow doesn't support compressed logs");
+        }
+        return follow(path);
+    }
+    let readers = paths
+        .iter()
+        .map(|path| open_log(Path::new(path)).map(LogReader::new))
+        .collect::<io::Result<Vec<_>>>()?;
+    let mut stdout = io::stdout().lock();
+    for entry in MergedReader::new(readers) {
+        match entry {
+            Ok(entry) => stdout.write_all(render_entry(&entry).as_bytes())?,
+            Err(ReadError::Io(e)) => return Err(e),
+            Err(e @ ReadError::Incomplete { .. }) => {
+                eprintln!("{e} (it may still be being written)")
+            }
+            Err(e) => {
+                eprintln!("{e}");
+                return Ok(1);
+            }
+        }
+    }
+    Ok(0)
+}
+
+/// Prints entries as they're appended to `path`, reopening it if it's rotated or truncated.
+/// A partially written entry is held back until the rest of it arrives.
+fn follow(path: &Path) -> io::Result<i32> {
+    let mut file = fs::File::open(path)?;
+    let mut id = file_id(&file.metadata()?);
+    let mut offset = 0;
+    let mut rotated_to = None;
+    let mut stdout = io::stdout().lock();
+    loop {
+        file.seek(SeekFrom::Start(offset))?;
+        let mut reader = LogReader::with_offset(&file, offset);
+        loop {
+            match reader.read_entry() {
+                Ok(Some(entry)) => stdout.write_all(render_entry(&entry).as_bytes())?,
+                Ok(None) | Err(ReadError::Incomplete { .. }) => break,
+                Err(ReadError::Io(e)) => return Err(e),
+                Err(e) => {
+                    eprintln!("{}: {e}", path.display());
+                    return Ok(1);
+                }
+            }
+        }
+        offset = reader.offset();
+        stdout.flush()?;
+
+        // The old file was drained after the rotation was noticed, switch to the new one
+        if let Some(new_file) = rotated_to.take() {
+            file = new_file;
+            id = file_id(&file.metadata()?);
+            offset = 0;
+            continue;
+        }
+        sleep(FOLLOW_POLL_INTERVAL);
+        match fs::metadata(path) {
+            Ok(metadata) if file_id(&metadata) != id => rotated_to = Some(fs::File::open(path)?),
+            Ok(metadata) if metadata.len() < offset => offset = 0, // Truncated in place
+            Ok(_) => {}
+            Err(_) => {} // Mid-rotation, the new file doesn't exist yet
+        }
+    }
+}
+
+/// The `--from`, `--to` and `--client` filters of `search` and `count`.
+struct EntryFilter<'a> {
+    from: u128,
+    to: u128,
+    client: Option<&'a str>,
+}
+
+impl<'a> EntryFilter<'a> {
+    fn parse(args: &'a Args) -> Result<Self, String> {
+        let mut bounds = [u128::MIN, u128::MAX];
+        for (bound, name) in bounds.iter_mut().zip(["from", "to"]) {
+            if let Some(value) = args.flag(name) {
+                match parse_timestamp(value) {
+                    Some(ts) => *bound = ts,
+                    None => return Err(format!("Invalid --{name} time: {value}")),
+                }
+            }
+        }
+        let [from, to] = bounds;
+        Ok(Self {
+            from,
+            to,
+            client: args.flag("client"),
+        })
+    }
+
+    fn matches(&self, entry: &LogEntry) -> bool {
+        (self.from..=self.to).contains(&entry.timestamp)
+            && self.client.is_none_or(|c| client_matches(&entry.client, c))
+    }
+
+    /// Whether no more entries can match, the log being (roughly) in time order.
+    fn is_past(&self, entry: &LogEntry) -> bool {
+        entry.timestamp > self.to.saturating_add(SEARCH_EARLY_EXIT_SLACK_MS)
+    }
+}
+
+fn search(args: &Args) -> io::Result<i32> {
+    let Some(path) = args.positional.first() else {
+        return usage_error("Missing file to search");
+    };
+    let filter = match EntryFilter::parse(args) {
+        Ok(filter) => filter,
+        Err(e) => return usage_error(&e),
+    };
+    let json = args.flag("json").is_some();
+
+    let mut stdout = io::stdout().lock();
+    for entry in LogReader::new(open_log(Path::new(path))?) {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(ReadError::Io(e)) => return Err(e),
+            Err(e @ ReadError::Incomplete { .. }) => {
+                eprintln!("{path}: {e} (it may still be being written)");
+                break;
+            }
+            Err(e) => {
+                eprintln!("{path}: {e}");
+                return Ok(1);
+            }
+        };
+        if filter.is_past(&entry) {
+            break;
+        }
+        if filter.matches(&entry) {
+            let rendered = if json {
+                render_entry_json(&entry)
+            } else {
+                render_entry(&entry)
+            };
+            stdout.write_all(rendered.as_bytes())?;
+        }
+    }
+    Ok(0)
+}
+
+/// Prints how many messages match the filters of `search`, and their total payload size.
+/// Run markers aren't messages, so they're never counted. A `DEDUP=global` duplicate counts
+/// with the size of the payload it stands for.
+fn count(args: &Args) -> io::Result<i32> {
+    let Some(path) = args.positional.first() else {
+        return usage_error("Missing file to count");
+    };
+    let filter = match EntryFilter::parse(args) {
+        Ok(filter) => filter,
+        Err(e) => return usage_error(&e),
+    };
+    let (mut records, mut bytes) = (0u64, 0usize);
+    let mut duplicates = DuplicateResolver::new(DEFAULT_DEDUP_WINDOW_BYTES);
+    for entry in LogReader::new(open_log(Path::new(path))?) {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(ReadError::Io(e)) => return Err(e),
+            Err(e @ ReadError::Incomplete { .. }) => {
+                eprintln!("{path}: {e} (it may still be being written)");
+                break;
+            }
+            Err(e) => {
+                eprintln!("{path}: {e}");
+                return Ok(1);
+            }
+        };
+        if filter.is_past(&entry) {
+            break;
+        }
+        let Some(payload) = duplicates.resolve(&entry) else {
+            warn_unresolved(path, &entry);
+            continue;
+        };
+        if filter.matches(&entry) && entry.run_marker().is_none() {
+            records += 1;
+            bytes += payload.len();
+        }
+    }
+    println!("{records} records, {}", human_readable_size(bytes));
+    Ok(0)
+}
+
+fn warn_unresolved(path: &str, entry: &LogEntry) {
+    eprintln!(
+        "Warning: {path}: skipped the duplicate at offset {}, the record it points at isn't in the file",
+        entry.offset
+    );
+}
+
+/// Sends one payload on a fresh connection, retrying with exponential backoff.
+fn send_payload(addr: &str, payload: &[u8]) -> io::Result<()> {
+    let mut backoff = REPLAY_INITIAL_BACKOFF;
+    let mut attempt = 0;
+    loop {
+        let result = TcpStream::connect(addr).and_then(|mut stream| {
+            stream.write_all(payload)?;
+            stream.shutdown(Shutdown::Write)
+        });
+        match result {
+            Ok(()) => return Ok(()),
+            Err(e) if attempt < REPLAY_MAX_RETRIES => {
+                attempt += 1;
+                eprintln!("Failed to send to {addr} ({e}), retrying in {backoff:?}");
+                sleep(backoff);
+                backoff = (backoff * 2).min(REPLAY_MAX_BACKOFF);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+fn replay(args: &Args) -> io::Result<i32> {
+    let Some(path) = args.positional.first() else {
+        return usage_error("Missing file to replay");
+    };
+    let Some(addr) = args.flag("to").filter(|addr| !addr.is_empty()) else {
+        return usage_error("Missing --to <addr>");
+    };
+    let realtime = args.flag("realtime").is_some();
+    let mut sent = 0;
+    let mut previous: Option<u128> = None;
+    let mut status = 0;
+    let mut duplicates = DuplicateResolver::new(DEFAULT_DEDUP_WINDOW_BYTES);
+    for entry in LogReader::new(open_log(Path::new(path))?) {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(ReadError::Io(e)) => return Err(e),
+            Err(e @ ReadError::Incomplete { .. }) => {
+                eprintln!("{path}: {e} (it may still be being written)");
+                break;
+            }
+            Err(e) => {
+                eprintln!("{path}: {e}");
+                status = 1;
+                break;
+            }
+        };
+        if entry.run_marker().is_some() {
+            continue; // Not a client payload
+        }
+        let Some(payload) = duplicates.resolve(&entry) else {
+            warn_unresolved(path, &entry);
+            continue;
+        };
+        if realtime {
+            if let Some(previous) = previous {
+                let gap = entry.timestamp.saturating_sub(previous);
+                sleep(Duration::from_millis(gap as u64));
+            }
+            previous = Some(entry.timestamp);
+        }
+        if let Err(e) = send_payload(addr, payload) {
+            eprintln!("Giving up on {addr}: {e}");
+            status = 1;
+            break;
+        }
+        sent += 1;
+    }
+    println!("Sent {sent} messages to {addr}");
+    Ok(status)
+}
+
+fn verify(args: &Args) -> io::Result<i32> {
+    let Some(path) = args.positional.first() else {
+        return usage_error("Missing file to verify");
+    };
+    let file = Path::new(path);
+    let report = verify_log(open_log(file)?)?;
+    println!(
+        "{path}: {} good records, {} bad records",
+        report.good, report.bad
+    );
+    if let Some((offset, reason)) = &report.first_bad {
+        println!("First bad record at offset {offset}: {reason}");
+    }
+    if report.runs > 0 {
+        println!(
+            "{} server runs, {} without a clean shutdown",
+            report.runs, report.unclean_stops
+        );
+    }
+    if let Some(offset) = report.incomplete_tail {
+        println!("Incomplete tail at offset {offset} (the last record may still be being written)");
+    }
+    if !is_finished_compressed(file)? {
+        println!("The compressed stream ends early (it may still be being written, or the server stopped uncleanly), records up to there were checked");
+    }
+    Ok(if report.is_ok() { 0 } else { 1 })
+}
+
+/// Matches the active log and its archives, e.g. `messages*.log*` for the default `LOG_FILE`.
+/// A log file without an extension matches itself and the files named after it, e.g.
+/// `messages` and `messages.1718000000000.gz`.
+fn is_log_file_name(name: &str, log_file: &Path) -> bool {
+    let stem = log_file.file_stem().unwrap_or_default().to_string_lossy();
+    let Some(rest) = name.strip_prefix(stem.as_ref()) else {
+        return false;
+    };
+    match log_file.extension() {
+        Some(ext) => rest.contains(&format!(".{}", ext.to_string_lossy())),
+        None => rest.is_empty() || rest.starts_with('.'),
+    }
+}
+
+fn list(args: &Args) -> io::Result<i32> {
+    let Some(dir) = args.positional.first() else {
+        return usage_error("Missing directory to list");
+    };
+    let sort = args.flag("sort").unwrap_or("name");
+    if sort != "name" && sort != "size" {
+        return usage_error(&format!("Unknown sort order: {sort}"));
+    }
+    let source = ConfigSource::load(&[]).map_err(io::Error::other)?;
+    let config = ServerConfig::from_source(&source).map_err(io::Error::other)?;
+    let log_file = Path::new(&config.log_file);
+    let mut files: Vec<(PathBuf, u64)> = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        if entry.file_type()?.is_file() && is_log_file_name(&name.to_string_lossy(), log_file) {
+            files.push((entry.path(), entry.metadata()?.len()));
+        }
+    }
+    match sort {
+        "size" => files.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0))),
+        _ => files.sort(),
+    }
+
+    println!(
+        "{:<40} {:>10} {:>12} {:>8}  {:<24}  LAST",
+        "NAME", "SIZE", "UNCOMPRESSED", "RECORDS", "FIRST"
+    );
+    let (mut total_size, mut total_uncompressed, mut total_records) = (0, 0, 0);
+    for (path, size) in &files {
+        let summary = summarize_log(open_log(path)?)?;
+        let name = path
+            .file_name()
+            .map(|n| n.to_string_lossy())
+            .unwrap_or_default();
+        let timestamp = |ts: Option<u128>| ts.map(format_timestamp).unwrap_or("-".to_string());
+        println!(
+            "{:<40} {:>10} {:>12} {:>8}  {:<24}  {:<24}{}",
+            name,
+            human_readable_size(*size as usize),
+            human_readable_size(summary.bytes as usize),
+            summary.records,
+            timestamp(summary.first_timestamp),
+            timestamp(summary.last_timestamp),
+            summary
+                .error
+                .map(|e| format!("  ({e})"))
+                .unwrap_or_default()
+        );
+        total_size += size;
+        total_uncompressed += summary.bytes;
+        total_records += summary.records;
+    }
+    println!(
+        "{:<40} {:>10} {:>12} {:>8}",
+        format!("Total ({} files)", files.len()),
+        human_readable_size(total_size as usize),
+        human_readable_size(total_uncompressed as usize),
+        total_records
+    );
+    Ok(0)
+}
@@ -0,0 +1,971 @@
+//! Server configuration, gathered from (lowest to highest precedence):
+//!
+//! 1. Built-in defaults
+//! 2. An optional TOML file, given by `--config <path>` or the `CONFIG_FILE` env var.
+//!    Keys are the env var names in any case, e.g. `log_file = "messages.log"` or `ports = [8001, 8002]`.
+//!    Values are typed (lists are arrays), a file with an invalid one isn't loaded
+//! 3. Environment variables, e.g. `LOG_FILE=messages.log`
+//! 4. Command line flags, e.g. `--log-file messages.log` or `--log-file=messages.log`
+
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::{env, fs, process};
+
+use serde::{Deserialize, Deserializer};
+
+use crate::error::ScooperError;
+use crate::{
+    hostname, stamp_safe, unescape, CompressAlgo, DedupMode, EmptyMessage, FormatRule, Framing,
+    IngestChecksum, IngestCompress, LogFormat, OnFull, OpenMode, RecordSeparator, SanitizeMode,
+    ShardPolicy, StampMode, DEFAULT_RATE_WINDOW_SECS,
+};
+
+pub const DEFAULT_PORT: u16 = 8001;
+pub const DEFAULT_LOG_FILE: &str = "messages.log";
+pub const DEFAULT_MAX_LOG_SIZE: usize = 50 * 1024 * 1024; // 50 MB
+pub const DEFAULT_ROTATE_ON_START_FRACTION: f64 = 0.9;
+pub const DEFAULT_METRICS_PORT: u16 = 0; // 0 disables the metrics endpoint
+pub const DEFAULT_STATSD_INTERVAL_SECS: u64 = 10;
+pub const DEFAULT_STATS_INTERVAL_SECS: u64 = 0; // 0 disables the periodic stats line
+pub const DEFAULT_FLUSH_INTERVAL_MS: u64 = 0; // 0 flushes after every message
+pub const DEFAULT_WRITE_SHARDS: usize = 1;
+pub const DEFAULT_CONNECTION_BUFFER_BYTES: usize = 0; // 0 writes every message right away
+pub const DEFAULT_CONNECTION_BUFFER_MS: u64 = 100;
+pub const DEFAULT_DEDUP_WINDOW: usize = 1024; // Payloads remembered by DEDUP=global
+pub const DEFAULT_DEDUP_WINDOW_BYTES: usize = 64 * 1024 * 1024; // Their total size at most
+pub const DEFAULT_MAX_CLIENT_FIELD_LEN: usize = 0; // 0 keeps the whole client identity
+pub const DEFAULT_MIN_BYTES_PER_SEC: u64 = 0; // 0 disables the slow client guard
+pub const DEFAULT_WRITE_STALL_MS: u64 = 0; // 0 disables the write stall warning
+pub const DEFAULT_FULL_WARN_SECS: u64 = 0; // 0 disables the time to full projection
+pub const DEFAULT_RETENTION_SECS: u64 = 0; // 0 keeps records forever
+pub const DEFAULT_RETENTION_INTERVAL_SECS: u64 = 300;
+pub const DEFAULT_RECV_BUFFER_BYTES: usize = 0; // 0 keeps the OS default
+pub const DEFAULT_LISTEN_BACKLOG: u32 = 1024; // Tokio's default
+pub const DEFAULT_ACCEPT_TASKS: usize = 1;
+pub const DEFAULT_MAX_CONNECTIONS: u64 = 0; // 0 accepts any number of connections
+pub const DEFAULT_MAX_ENTRIES_PER_FILE: u64 = 0; // 0 only rotates on size, see ON_FULL
+pub const DEFAULT_BUSY_MESSAGE: &str = "busy\n"; // Sent to connections over MAX_CONNECTIONS
+pub const DEFAULT_WORKER_THREADS: usize = 0; // 0 keeps Tokio's default (one per CPU core)
+const SD_LISTEN_FDS_START: i32 = 3; // The first descriptor passed by systemd socket activation
+
+fn invalid_config(message: String) -> ScooperError {
+    ScooperError::Config(message)
+}
+
+/// Whether a file can be created in `dir`, which is more reliable than checking its permissions.
+fn is_writable(dir: &Path) -> bool {
+    let probe = dir.join(format!(".scooper-write-test-{}", process::id()));
+    match fs::File::create(&probe) {
+        Ok(_) => fs::remove_file(&probe).is_ok(),
+        Err(_) => false,
+    }
+}
+
+/// `COMPRESS_LEVEL`, clamped to the levels `algo` accepts.
+fn compress_level(source: &ConfigSource, algo: CompressAlgo) -> u32 {
+    let levels = algo.levels();
+    let default = source.file.compress_level.unwrap_or(algo.default_level());
+    let level = source.get("COMPRESS_LEVEL", default);
+    let clamped = level.clamp(*levels.start(), *levels.end());
+    if clamped != level {
+        eprintln!(
+            "Warning: COMPRESS_LEVEL {level} is out of range for {}, using {clamped}",
+            algo.as_str()
+        );
+    }
+    clamped
+}
+
+/// Listeners inherited from the parent process: the descriptors in `SCOOPER_LISTEN_FD`, or
+/// the ones systemd passes with `LISTEN_FDS` when `LISTEN_PID` is this process.
+fn listen_fds(source: &ConfigSource) -> Vec<i32> {
+    if source.raw("SCOOPER_LISTEN_FD").is_some() {
+        return source.get_list("SCOOPER_LISTEN_FD", Vec::new());
+    }
+    let for_us = source
+        .raw("LISTEN_PID")
+        .is_some_and(|pid| pid.trim() == process::id().to_string());
+    match source.raw("LISTEN_FDS") {
+        Some(_) if !for_us => Vec::new(), // Meant for another process, e.g. our parent
+        Some(_) => {
+            let count: i32 = source.get("LISTEN_FDS", 0);
+            (SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + count).collect()
+        }
+        None => Vec::new(),
+    }
+}
+
+/// Settings that are parsed from strings are read from strings in the config file too.
+macro_rules! deserialize_from_str {
+    ($($ty:ty),* $(,)?) => {$(
+        impl<'de> Deserialize<'de> for $ty {
+            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                String::deserialize(deserializer)?
+                    .parse()
+                    .map_err(serde::de::Error::custom)
+            }
+        }
+    )*};
+}
+
+deserialize_from_str!(
+    CompressAlgo,
+    DedupMode,
+    EmptyMessage,
+    FormatRule,
+    Framing,
+    IngestChecksum,
+    IngestCompress,
+    LogFormat,
+    OnFull,
+    OpenMode,
+    RecordSeparator,
+    SanitizeMode,
+    ShardPolicy,
+    StampMode,
+);
+
+/// The settings of the TOML config file, keyed by their env var names in lower case.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct FileSettings {
+    port: Option<u16>,
+    ports: Option<Vec<u16>>,
+    listen: Option<Vec<SocketAddr>>,
+    log_file: Option<String>,
+    create_log_dir: Option<bool>,
+    max_file_size: Option<usize>,
+    open_mode: Option<OpenMode>,
+    on_full: Option<OnFull>,
+    rotate_on_start: Option<bool>,
+    max_entries_per_file: Option<u64>,
+    rotate_on_start_fraction: Option<f64>,
+    metrics_port: Option<u16>,
+    statsd_addr: Option<String>,
+    log_fifo: Option<String>,
+    upstream_addrs: Option<Vec<String>>,
+    upstream_compress: Option<bool>,
+    statsd_interval_secs: Option<u64>,
+    stats_interval_secs: Option<u64>,
+    rate_window_secs: Option<u64>,
+    flush_interval_ms: Option<u64>,
+    retention_secs: Option<u64>,
+    retention_interval_secs: Option<u64>,
+    worker_threads: Option<usize>,
+    listen_backlog: Option<u32>,
+    accept_tasks: Option<usize>,
+    max_connections: Option<u64>,
+    busy_message: Option<String>,
+    write_shards: Option<usize>,
+    shard_policy: Option<ShardPolicy>,
+    client_affinity: Option<bool>,
+    recv_buffer_bytes: Option<usize>,
+    keep_alive: Option<bool>,
+    proxy_protocol: Option<bool>,
+    framing: Option<Framing>,
+    ingest_checksum: Option<IngestChecksum>,
+    empty_message: Option<EmptyMessage>,
+    ingest_compress: Option<IngestCompress>,
+    reverse_dns: Option<bool>,
+    max_client_field_len: Option<usize>,
+    min_bytes_per_sec: Option<u64>,
+    write_stall_ms: Option<u64>,
+    full_warn_secs: Option<u64>,
+    connection_buffer_bytes: Option<usize>,
+    connection_buffer_ms: Option<u64>,
+    sanitize: Option<SanitizeMode>,
+    content_tag: Option<bool>,
+    stamp_human_size: Option<bool>,
+    compress_log: Option<bool>,
+    compress_algo: Option<CompressAlgo>,
+    compress_level: Option<u32>,
+    dedup: Option<DedupMode>,
+    dedup_window: Option<usize>,
+    dedup_window_bytes: Option<usize>,
+    stamp: Option<StampMode>,
+    log_format: Option<LogFormat>,
+    format_map: Option<Vec<FormatRule>>,
+    record_separator: Option<RecordSeparator>,
+    separator: Option<String>,
+    run_markers: Option<bool>,
+    instance_tag: Option<String>,
+    check_config: Option<bool>,
+    #[serde(flatten)]
+    unknown: toml::Table,
+}
+
+impl FileSettings {
+    fn parse(content: &str) -> Result<Self, toml::de::Error> {
+        let table: toml::Table = content.parse()?;
+        let table: toml::Table = table
+            .into_iter()
+            .map(|(key, value)| (key.to_ascii_lowercase(), value))
+            .collect();
+        toml::Value::Table(table).try_into()
+    }
+}
+
+/// The config file, and the raw values of the command line flags keyed by their env var name.
+#[derive(Debug, Default)]
+pub struct ConfigSource {
+    file: FileSettings,
+    cli: HashMap<String, String>,
+    accessed: RefCell<HashSet<String>>,
+}
+
+impl ConfigSource {
+    /// Reads the TOML config file (if one was given) and the command line flags.
+    pub fn load(args: &[String]) -> Result<Self, ScooperError> {
+        let mut source = Self::default();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let Some(flag) = arg.strip_prefix("--") else {
+                return Err(invalid_config(format!("Unexpected argument: {arg}")));
+            };
+            if flag == "check-config" {
+                source.cli.insert("CHECK_CONFIG".into(), "true".into()); // Takes no value
+                continue;
+            }
+            let (name, value) = match flag.split_once('=') {
+                Some((name, value)) => (name, value.to_string()),
+                None => match args.next() {
+                    Some(value) => (flag, value.clone()),
+                    None => return Err(invalid_config(format!("Missing value for --{flag}"))),
+                },
+            };
+            let key = name.replace('-', "_").to_ascii_uppercase();
+            source.cli.insert(key, value);
+        }
+
+        let config_file = source
+            .cli
+            .remove("CONFIG")
+            .or_else(|| env::var("CONFIG_FILE").ok());
+        if let Some(path) = config_file {
+            let content = fs::read_to_string(&path)
+                .map_err(|e| invalid_config(format!("Failed to read config file {path}: {e}")))?;
+            source.file = FileSettings::parse(&content)
+                .map_err(|e| invalid_config(format!("Invalid config file {path}: {e}")))?;
+        }
+        Ok(source)
+    }
+
+    /// The value of a command line flag, or else of an env var. Config file values are
+    /// typed, they're the defaults that these override.
+    pub fn raw(&self, key: &str) -> Option<String> {
+        self.accessed.borrow_mut().insert(key.to_string());
+        self.cli.get(key).cloned().or_else(|| env::var(key).ok())
+    }
+
+    pub fn get<T: FromStr>(&self, key: &str, default: T) -> T {
+        match self.raw(key) {
+            Some(value) => value.trim().parse().unwrap_or_else(|_| {
+                eprintln!("Warning: ignoring invalid value for {key}: {value:?}");
+                default
+            }),
+            None => default,
+        }
+    }
+
+    pub fn get_list<T: FromStr>(&self, key: &str, default: Vec<T>) -> Vec<T> {
+        let Some(value) = self.raw(key) else {
+            return default;
+        };
+        match value
+            .split(',')
+            .map(|item| item.trim().parse().ok())
+            .collect::<Option<Vec<T>>>()
+        {
+            Some(items) if !items.is_empty() => items,
+            _ => {
+                eprintln!("Warning: ignoring invalid value for {key}: {value:?}");
+                default
+            }
+        }
+    }
+
+    /// Keys given in the config file or on the command line that aren't settings.
+    pub fn unused_keys(&self) -> Vec<String> {
+        let accessed = self.accessed.borrow();
+        let mut unused: Vec<String> = self
+            .cli
+            .keys()
+            .filter(|key| !accessed.contains(*key))
+            .cloned()
+            .chain(self.file.unknown.keys().map(|key| key.to_ascii_uppercase()))
+            .collect();
+        unused.sort();
+        unused.dedup();
+        unused
+    }
+}
+
+/// The settings that a `SIGHUP` applies to a running server, see `ServerConfig::reloaded`.
+pub const RELOADABLE_SETTINGS: &[&str] = &[
+    "SANITIZE",
+    "CONTENT_TAG",
+    "STAMP_HUMAN_SIZE",
+    "INSTANCE_TAG",
+    "SEPARATOR",
+    "LOG_FORMAT",
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServerConfig {
+    pub listen: Vec<SocketAddr>,
+    pub listen_fds: Vec<i32>, // Inherited listeners, used instead of binding `listen`
+    pub log_file: String,
+    pub create_log_dir: bool,
+    pub max_log_size: usize,
+    pub open_mode: OpenMode,
+    pub on_full: OnFull,
+    pub rotate_on_start: bool,
+    pub max_entries_per_file: u64,
+    pub rotate_on_start_fraction: f64,
+    pub metrics_port: u16,
+    pub statsd_addr: Option<String>,
+    pub log_fifo: Option<String>,
+    pub upstream_addrs: Vec<String>,
+    pub upstream_compress: bool,
+    pub statsd_interval_secs: u64,
+    pub stats_interval_secs: u64,
+    pub rate_window_secs: u64,
+    pub flush_interval_ms: u64,
+    pub retention_secs: u64,
+    pub retention_interval_secs: u64,
+    pub worker_threads: usize,
+    pub listen_backlog: u32,
+    pub accept_tasks: usize,
+    pub max_connections: u64,
+    pub busy_message: String, // Empty closes rejected connections without a word
+    pub write_shards: usize,
+    pub shard_policy: ShardPolicy,
+    // Places clients by the hash of SHARD_POLICY whatever it is, at the cost of balance
+    pub client_affinity: bool,
+    pub recv_buffer_bytes: usize,
+    pub keep_alive: bool,
+    pub proxy_protocol: bool,
+    pub framing: Framing,
+    pub ingest_checksum: IngestChecksum,
+    pub empty_message: EmptyMessage,
+    pub ingest_compress: IngestCompress,
+    pub reverse_dns: bool, // Puts the client's host name before its address in stamps
+    pub max_client_field_len: usize,
+    pub min_bytes_per_sec: u64,
+    pub write_stall_ms: u64,
+    pub full_warn_secs: u64, // Warn when the log is projected to fill up sooner than this
+    pub connection_buffer_bytes: usize,
+    pub connection_buffer_ms: u64,
+    pub sanitize: SanitizeMode,
+    pub tag_content: bool,
+    pub stamp_human_size: bool,
+    pub compress_log: bool, // Always false with COMPRESS_ALGO=none
+    pub compress_algo: CompressAlgo,
+    pub compress_level: u32,
+    pub dedup: DedupMode,
+    pub dedup_window: usize,
+    pub dedup_window_bytes: usize,
+    pub stamp: StampMode,
+    pub log_format: LogFormat,
+    pub format_map: Vec<FormatRule>,
+    pub record_separator: RecordSeparator,
+    pub separator: String,
+    pub run_markers: bool,
+    pub instance_tag: Option<String>, // Stamp safe, `None` if INSTANCE_TAG is empty
+    pub check_config: bool,           // Only validate the settings, see `check_addrs`
+}
+
+impl ServerConfig {
+    /// Loads the config file and command line flags on top of the environment,
+    /// warning about any keys that aren't known settings.
+    pub fn load(args: &[String]) -> Result<Self, ScooperError> {
+        let source = ConfigSource::load(args)?;
+        let config = Self::from_source(&source)?;
+        for key in source.unused_keys() {
+            eprintln!("Warning: ignoring unknown setting {key}");
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// These settings, with the [`RELOADABLE_SETTINGS`] of `new`.
+    pub fn reloaded(&self, new: &Self) -> Self {
+        Self {
+            sanitize: new.sanitize,
+            tag_content: new.tag_content,
+            stamp_human_size: new.stamp_human_size,
+            instance_tag: new.instance_tag.clone(),
+            separator: new.separator.clone(),
+            log_format: match self.log_format_reloads(new) {
+                true => new.log_format,
+                false => self.log_format,
+            },
+            ..self.clone()
+        }
+    }
+
+    /// Whether the `LOG_FORMAT` of `new` applies live. It can't if `FORMAT_MAP` maps clients
+    /// to either format, their files would be shared with the ones switching formats.
+    fn log_format_reloads(&self, new: &Self) -> bool {
+        !self
+            .format_map
+            .iter()
+            .any(|rule| rule.format == self.log_format || rule.format == new.log_format)
+    }
+
+    /// The settings that differ in `new` and only take effect on a restart.
+    pub fn restart_only_changes(&self, new: &Self) -> Vec<&'static str> {
+        // No `..`, so a new setting has to be either listed here or reloadable
+        let Self {
+            listen,
+            listen_fds,
+            log_file,
+            create_log_dir,
+            max_log_size,
+            open_mode,
+            on_full,
+            rotate_on_start,
+            max_entries_per_file,
+            rotate_on_start_fraction,
+            metrics_port,
+            statsd_addr,
+            log_fifo,
+            upstream_addrs,
+            upstream_compress,
+            statsd_interval_secs,
+            stats_interval_secs,
+            rate_window_secs,
+            flush_interval_ms,
+            retention_secs,
+            retention_interval_secs,
+            worker_threads,
+            listen_backlog,
+            accept_tasks,
+            max_connections,
+            busy_message,
+            write_shards,
+            shard_policy,
+            client_affinity,
+            recv_buffer_bytes,
+            keep_alive,
+            proxy_protocol,
+            framing,
+            ingest_checksum,
+            empty_message,
+            ingest_compress,
+            reverse_dns,
+            max_client_field_len,
+            min_bytes_per_sec,
+            write_stall_ms,
+            full_warn_secs,
+            connection_buffer_bytes,
+            connection_buffer_ms,
+            sanitize: _,
+            tag_content: _,
+            stamp_human_size: _,
+            compress_log,
+            compress_algo,
+            compress_level,
+            dedup,
+            dedup_window,
+            dedup_window_bytes,
+            stamp,
+            log_format,
+            format_map,
+            record_separator,
+            separator: _,
+            run_markers,
+            instance_tag: _,
+            check_config: _, // Only used before the server starts
+        } = self;
+        let changed = [
+            ("LISTEN", *listen != new.listen),
+            ("LISTEN_FDS", *listen_fds != new.listen_fds),
+            ("LOG_FILE", *log_file != new.log_file),
+            ("CREATE_LOG_DIR", *create_log_dir != new.create_log_dir),
+            ("MAX_FILE_SIZE", *max_log_size != new.max_log_size),
+            ("OPEN_MODE", *open_mode != new.open_mode),
+            ("ON_FULL", *on_full != new.on_full),
+            ("ROTATE_ON_START", *rotate_on_start != new.rotate_on_start),
+            (
+                "MAX_ENTRIES_PER_FILE",
+                *max_entries_per_file != new.max_entries_per_file,
+            ),
+            (
+                "ROTATE_ON_START_FRACTION",
+                *rotate_on_start_fraction != new.rotate_on_start_fraction,
+            ),
+            ("METRICS_PORT", *metrics_port != new.metrics_port),
+            ("STATSD_ADDR", *statsd_addr != new.statsd_addr),
+            ("LOG_FIFO", *log_fifo != new.log_fifo),
+            ("UPSTREAM_ADDRS", *upstream_addrs != new.upstream_addrs),
+            (
+                "UPSTREAM_COMPRESS",
+                *upstream_compress != new.upstream_compress,
+            ),
+            (
+                "STATSD_INTERVAL_SECS",
+                *statsd_interval_secs != new.statsd_interval_secs,
+            ),
+            (
+                "STATS_INTERVAL_SECS",
+                *stats_interval_secs != new.stats_interval_secs,
+            ),
+            (
+                "RATE_WINDOW_SECS",
+                *rate_window_secs != new.rate_window_secs,
+            ),
+            (
+                "FLUSH_INTERVAL_MS",
+                *flush_interval_ms != new.flush_interval_ms,
+            ),
+            ("RETENTION_SECS", *retention_secs != new.retention_secs),
+            (
+                "RETENTION_INTERVAL_SECS",
+                *retention_interval_secs != new.retention_interval_secs,
+            ),
+            ("WORKER_THREADS", *worker_threads != new.worker_threads),
+            ("LISTEN_BACKLOG", *listen_backlog != new.listen_backlog),
+            ("ACCEPT_TASKS", *accept_tasks != new.accept_tasks),
+            ("MAX_CONNECTIONS", *max_connections != new.max_connections),
+            ("BUSY_MESSAGE", *busy_message != new.busy_message),
+            ("WRITE_SHARDS", *write_shards != new.write_shards),
+            ("SHARD_POLICY", *shard_policy != new.shard_policy),
+            ("CLIENT_AFFINITY", *client_affinity != new.client_affinity),
+            (
+                "RECV_BUFFER_BYTES",
+                *recv_buffer_bytes != new.recv_buffer_bytes,
+            ),
+            ("KEEP_ALIVE", *keep_alive != new.keep_alive),
+            ("PROXY_PROTOCOL", *proxy_protocol != new.proxy_protocol),
+            ("FRAMING", *framing != new.framing),
+            ("INGEST_CHECKSUM", *ingest_checksum != new.ingest_checksum),
+            ("EMPTY_MESSAGE", *empty_message != new.empty_message),
+            ("INGEST_COMPRESS", *ingest_compress != new.ingest_compress),
+            ("REVERSE_DNS", *reverse_dns != new.reverse_dns),
+            (
+                "MAX_CLIENT_FIELD_LEN",
+                *max_client_field_len != new.max_client_field_len,
+            ),
+            (
+                "MIN_BYTES_PER_SEC",
+                *min_bytes_per_sec != new.min_bytes_per_sec,
+            ),
+            ("WRITE_STALL_MS", *write_stall_ms != new.write_stall_ms),
+            ("FULL_WARN_SECS", *full_warn_secs != new.full_warn_secs),
+            (
+                "CONNECTION_BUFFER_BYTES",
+                *connection_buffer_bytes != new.connection_buffer_bytes,
+            ),
+            (
+                "CONNECTION_BUFFER_MS",
+                *connection_buffer_ms != new.connection_buffer_ms,
+            ),
+            ("COMPRESS_LOG", *compress_log != new.compress_log),
+            ("COMPRESS_ALGO", *compress_algo != new.compress_algo),
+            ("COMPRESS_LEVEL", *compress_level != new.compress_level),
+            ("DEDUP", *dedup != new.dedup),
+            ("DEDUP_WINDOW", *dedup_window != new.dedup_window),
+            (
+                "DEDUP_WINDOW_BYTES",
+                *dedup_window_bytes != new.dedup_window_bytes,
+            ),
+            ("STAMP", *stamp != new.stamp),
+            ("FORMAT_MAP", *format_map != new.format_map),
+            (
+                "RECORD_SEPARATOR",
+                *record_separator != new.record_separator,
+            ),
+            ("RUN_MARKERS", *run_markers != new.run_markers),
+            (
+                "LOG_FORMAT",
+                *log_format != new.log_format && !self.log_format_reloads(new),
+            ),
+        ];
+        changed
+            .into_iter()
+            .filter(|(_, changed)| *changed)
+            .map(|(setting, _)| setting)
+            .collect()
+    }
+
+    /// Checks that the addresses the server connects to resolve, which startup leaves to
+    /// the connections themselves. Nothing is connected to or bound.
+    pub fn check_addrs(&self) -> Result<(), ScooperError> {
+        for addr in self.statsd_addr.iter().chain(&self.upstream_addrs) {
+            let resolved = addr
+                .to_socket_addrs()
+                .map_err(|e| invalid_config(format!("Can't resolve {addr}: {e}")))?;
+            if resolved.len() == 0 {
+                return Err(invalid_config(format!("{addr} resolves to no addresses")));
+            }
+        }
+        Ok(())
+    }
+
+    /// The directory the log files go in.
+    pub fn log_dir(&self) -> PathBuf {
+        match Path::new(&self.log_file).parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+            _ => PathBuf::from("."),
+        }
+    }
+
+    /// The named pipe the log is written to instead of a file, `LOG_FIFO` or a `LOG_FILE`
+    /// that is one.
+    pub fn fifo_path(&self) -> Option<PathBuf> {
+        if let Some(fifo) = &self.log_fifo {
+            return Some(PathBuf::from(fifo));
+        }
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::FileTypeExt;
+            let log_file = Path::new(&self.log_file);
+            if fs::metadata(log_file).is_ok_and(|m| m.file_type().is_fifo()) {
+                return Some(log_file.to_path_buf());
+            }
+        }
+        None
+    }
+
+    /// Checks the settings that can't be checked while parsing them.
+    pub fn validate(&self) -> Result<(), ScooperError> {
+        if cfg!(not(unix)) && !self.listen_fds.is_empty() {
+            return Err(invalid_config(
+                "Inherited listeners are only supported on Unix".into(),
+            ));
+        }
+        if self.fifo_path().is_some() {
+            if cfg!(not(unix)) {
+                return Err(invalid_config(
+                    "FIFO logs are only supported on Unix".into(),
+                ));
+            }
+            // A FIFO is a single stream that can't be renamed or rewritten
+            let conflicts = [
+                ("WRITE_SHARDS", self.write_shards > 1),
+                ("FORMAT_MAP", !self.format_map.is_empty()),
+                ("COMPRESS_LOG", self.compress_log),
+                ("ON_FULL=rotate", self.on_full == OnFull::Rotate),
+                ("RETENTION_SECS", self.retention_secs > 0),
+                ("ROTATE_ON_START", self.rotate_on_start),
+                ("MAX_ENTRIES_PER_FILE", self.max_entries_per_file > 0),
+            ];
+            if let Some((setting, _)) = conflicts.iter().find(|(_, set)| *set) {
+                return Err(invalid_config(format!(
+                    "{setting} can't be used with a FIFO log"
+                )));
+            }
+            return Ok(());
+        }
+        let log_dir = self.log_dir();
+        // A missing directory is checked again when CREATE_LOG_DIR tries to create it
+        let usable = match log_dir.is_dir() {
+            true => is_writable(&log_dir),
+            false => self.create_log_dir,
+        };
+        if !usable {
+            return Err(invalid_config(format!(
+                "log directory {} does not exist or is not writable",
+                log_dir.display()
+            )));
+        }
+        Ok(())
+    }
+
+    pub fn from_source(source: &ConfigSource) -> Result<Self, ScooperError> {
+        let file = &source.file;
+        let port = source.get("PORT", file.port.unwrap_or(DEFAULT_PORT));
+        let ports = source.get_list("PORTS", file.ports.clone().unwrap_or(vec![port]));
+        let compress_algo = source.get("COMPRESS_ALGO", file.compress_algo.unwrap_or_default());
+        let listen = match source.raw("LISTEN") {
+            Some(list) => list
+                .split(',')
+                .map(|item| {
+                    item.trim().parse().map_err(|e| {
+                        invalid_config(format!("Invalid LISTEN address {item:?}: {e}"))
+                    })
+                })
+                .collect::<Result<Vec<_>, _>>()?,
+            None => match &file.listen {
+                Some(listen) => listen.clone(),
+                None => ports
+                    .into_iter()
+                    .map(|port| SocketAddr::from(([0, 0, 0, 0], port)))
+                    .collect(),
+            },
+        };
+        Ok(Self {
+            listen,
+            listen_fds: listen_fds(source),
+            log_file: source.get(
+                "LOG_FILE",
+                file.log_file
+                    .clone()
+                    .unwrap_or_else(|| DEFAULT_LOG_FILE.to_string()),
+            ),
+            create_log_dir: source.get("CREATE_LOG_DIR", file.create_log_dir.unwrap_or_default()),
+            max_log_size: source.get(
+                "MAX_FILE_SIZE",
+                file.max_file_size.unwrap_or(DEFAULT_MAX_LOG_SIZE),
+            ),
+            open_mode: source.get("OPEN_MODE", file.open_mode.unwrap_or_default()),
+            on_full: source.get("ON_FULL", file.on_full.unwrap_or_default()),
+            rotate_on_start: source
+                .get("ROTATE_ON_START", file.rotate_on_start.unwrap_or_default()),
+            max_entries_per_file: source.get(
+                "MAX_ENTRIES_PER_FILE",
+                file.max_entries_per_file
+                    .unwrap_or(DEFAULT_MAX_ENTRIES_PER_FILE),
+            ),
+            rotate_on_start_fraction: source.get(
+                "ROTATE_ON_START_FRACTION",
+                file.rotate_on_start_fraction
+                    .unwrap_or(DEFAULT_ROTATE_ON_START_FRACTION),
+            ),
+            metrics_port: source.get(
+                "METRICS_PORT",
+                file.metrics_port.unwrap_or(DEFAULT_METRICS_PORT),
+            ),
+            statsd_addr: source
+                .raw("STATSD_ADDR")
+                .or_else(|| file.statsd_addr.clone()),
+            log_fifo: source.raw("LOG_FIFO").or_else(|| file.log_fifo.clone()),
+            upstream_addrs: source.get_list(
+                "UPSTREAM_ADDRS",
+                file.upstream_addrs.clone().unwrap_or_default(),
+            ),
+            upstream_compress: source.get(
+                "UPSTREAM_COMPRESS",
+                file.upstream_compress.unwrap_or_default(),
+            ),
+            statsd_interval_secs: source.get(
+                "STATSD_INTERVAL_SECS",
+                file.statsd_interval_secs
+                    .unwrap_or(DEFAULT_STATSD_INTERVAL_SECS),
+            ),
+            stats_interval_secs: source.get(
+                "STATS_INTERVAL_SECS",
+                file.stats_interval_secs
+                    .unwrap_or(DEFAULT_STATS_INTERVAL_SECS),
+            ),
+            rate_window_secs: source.get(
+                "RATE_WINDOW_SECS",
+                file.rate_window_secs.unwrap_or(DEFAULT_RATE_WINDOW_SECS),
+            ),
+            flush_interval_ms: source.get(
+                "FLUSH_INTERVAL_MS",
+                file.flush_interval_ms.unwrap_or(DEFAULT_FLUSH_INTERVAL_MS),
+            ),
+            retention_secs: source.get(
+                "RETENTION_SECS",
+                file.retention_secs.unwrap_or(DEFAULT_RETENTION_SECS),
+            ),
+            retention_interval_secs: source.get(
+                "RETENTION_INTERVAL_SECS",
+                file.retention_interval_secs
+                    .unwrap_or(DEFAULT_RETENTION_INTERVAL_SECS),
+            ),
+            worker_threads: source.get(
+                "WORKER_THREADS",
+                file.worker_threads.unwrap_or(DEFAULT_WORKER_THREADS),
+            ),
+            listen_backlog: source.get(
+                "LISTEN_BACKLOG",
+                file.listen_backlog.unwrap_or(DEFAULT_LISTEN_BACKLOG),
+            ),
+            accept_tasks: source.get(
+                "ACCEPT_TASKS",
+                file.accept_tasks.unwrap_or(DEFAULT_ACCEPT_TASKS),
+            ),
+            max_connections: source.get(
+                "MAX_CONNECTIONS",
+                file.max_connections.unwrap_or(DEFAULT_MAX_CONNECTIONS),
+            ),
+            busy_message: unescape(
+                &source
+                    .raw("BUSY_MESSAGE")
+                    .or_else(|| file.busy_message.clone())
+                    .unwrap_or_else(|| DEFAULT_BUSY_MESSAGE.to_string()),
+            ),
+            write_shards: source.get(
+                "WRITE_SHARDS",
+                file.write_shards.unwrap_or(DEFAULT_WRITE_SHARDS),
+            ),
+            shard_policy: source.get("SHARD_POLICY", file.shard_policy.unwrap_or_default()),
+            client_affinity: source
+                .get("CLIENT_AFFINITY", file.client_affinity.unwrap_or_default()),
+            recv_buffer_bytes: source.get(
+                "RECV_BUFFER_BYTES",
+                file.recv_buffer_bytes.unwrap_or(DEFAULT_RECV_BUFFER_BYTES),
+            ),
+            keep_alive: source.get("KEEP_ALIVE", file.keep_alive.unwrap_or_default()),
+            proxy_protocol: source.get("PROXY_PROTOCOL", file.proxy_protocol.unwrap_or_default()),
+            framing: source.get("FRAMING", file.framing.unwrap_or_default()),
+            ingest_checksum: source
+                .get("INGEST_CHECKSUM", file.ingest_checksum.unwrap_or_default()),
+            empty_message: source.get("EMPTY_MESSAGE", file.empty_message.unwrap_or_default()),
+            ingest_compress: source
+                .get("INGEST_COMPRESS", file.ingest_compress.unwrap_or_default()),
+            reverse_dns: source.get("REVERSE_DNS", file.reverse_dns.unwrap_or_default()),
+            max_client_field_len: source.get(
+                "MAX_CLIENT_FIELD_LEN",
+                file.max_client_field_len
+                    .unwrap_or(DEFAULT_MAX_CLIENT_FIELD_LEN),
+            ),
+            min_bytes_per_sec: source.get(
+                "MIN_BYTES_PER_SEC",
+                file.min_bytes_per_sec.unwrap_or(DEFAULT_MIN_BYTES_PER_SEC),
+            ),
+            write_stall_ms: source.get(
+                "WRITE_STALL_MS",
+                file.write_stall_ms.unwrap_or(DEFAULT_WRITE_STALL_MS),
+            ),
+            full_warn_secs: source.get(
+                "FULL_WARN_SECS",
+                file.full_warn_secs.unwrap_or(DEFAULT_FULL_WARN_SECS),
+            ),
+            connection_buffer_bytes: source.get(
+                "CONNECTION_BUFFER_BYTES",
+                file.connection_buffer_bytes
+                    .unwrap_or(DEFAULT_CONNECTION_BUFFER_BYTES),
+            ),
+            connection_buffer_ms: source.get(
+                "CONNECTION_BUFFER_MS",
+                file.connection_buffer_ms
+                    .unwrap_or(DEFAULT_CONNECTION_BUFFER_MS),
+            ),
+            sanitize: source.get("SANITIZE", file.sanitize.unwrap_or_default()),
+            tag_content: source.get("CONTENT_TAG", file.content_tag.unwrap_or_default()),
+            stamp_human_size: source.get(
+                "STAMP_HUMAN_SIZE",
+                file.stamp_human_size.unwrap_or_default(),
+            ),
+            compress_log: source.get("COMPRESS_LOG", file.compress_log.unwrap_or_default())
+                && compress_algo != CompressAlgo::None,
+            compress_algo,
+            compress_level: compress_level(source, compress_algo),
+            dedup: source.get("DEDUP", file.dedup.unwrap_or_default()),
+            dedup_window: source.get(
+                "DEDUP_WINDOW",
+                file.dedup_window.unwrap_or(DEFAULT_DEDUP_WINDOW),
+            ),
+            dedup_window_bytes: source.get(
+                "DEDUP_WINDOW_BYTES",
+                file.dedup_window_bytes
+                    .unwrap_or(DEFAULT_DEDUP_WINDOW_BYTES),
+            ),
+            stamp: source.get("STAMP", file.stamp.unwrap_or_default()),
+            log_format: source.get("LOG_FORMAT", file.log_format.unwrap_or_default()),
+            format_map: source.get_list("FORMAT_MAP", file.format_map.clone().unwrap_or_default()),
+            record_separator: source.get(
+                "RECORD_SEPARATOR",
+                file.record_separator.unwrap_or_default(),
+            ),
+            separator: unescape(
+                &source
+                    .raw("SEPARATOR")
+                    .or_else(|| file.separator.clone())
+                    .unwrap_or_default(),
+            ),
+            run_markers: source.get("RUN_MARKERS", file.run_markers.unwrap_or_default()),
+            instance_tag: match source
+                .raw("INSTANCE_TAG")
+                .or_else(|| file.instance_tag.clone())
+            {
+                Some(tag) => Some(tag.trim().to_string()).filter(|tag| !tag.is_empty()),
+                None => hostname(),
+            }
+            .map(|tag| stamp_safe(&tag)),
+            check_config: source.get("CHECK_CONFIG", file.check_config.unwrap_or_default()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn from_file(content: &str) -> (ServerConfig, ConfigSource) {
+        let source = ConfigSource {
+            file: FileSettings::parse(content).unwrap(),
+            ..ConfigSource::default()
+        };
+        (ServerConfig::from_source(&source).unwrap(), source)
+    }
+
+    #[test]
+    fn config_file_values_are_typed() {
+        let (config, source) = from_file(
+            r#"
+            LISTEN = ["127.0.0.1:9001", "[::1]:9002"]
+            Max_File_Size = 1000
+            sanitize = "ESCAPE"
+            format_map = ["10.0.0.0/8:json"]
+            rotate_on_start_fraction = 1
+            keep_alive = true
+            no_such_setting = 3
+            "#,
+        );
+        assert_eq!(
+            config.listen,
+            [
+                "127.0.0.1:9001".parse().unwrap(),
+                "[::1]:9002".parse().unwrap()
+            ]
+        );
+        assert_eq!(config.max_log_size, 1000);
+        assert_eq!(config.sanitize, SanitizeMode::Escape);
+        assert_eq!(config.format_map, ["10.0.0.0/8:json".parse().unwrap()]);
+        assert_eq!(config.rotate_on_start_fraction, 1.0);
+        assert!(config.keep_alive);
+        assert_eq!(source.unused_keys(), ["NO_SUCH_SETTING"]);
+    }
+
+    #[test]
+    fn config_file_ports_default_the_listen_addresses() {
+        let (config, _) = from_file("ports = [9101, 9102]");
+        let expected: Vec<SocketAddr> = vec![
+            "0.0.0.0:9101".parse().unwrap(),
+            "0.0.0.0:9102".parse().unwrap(),
+        ];
+        assert_eq!(config.listen, expected);
+    }
+
+    #[test]
+    fn reload_applies_log_format_and_reports_the_rest() {
+        let (running, _) = from_file("");
+        let (new, _) = from_file(
+            r#"
+            log_format = "json"
+            sanitize = "escape"
+            keep_alive = true
+            "#,
+        );
+        assert_eq!(running.restart_only_changes(&new), ["KEEP_ALIVE"]);
+        let reloaded = running.reloaded(&new);
+        assert_eq!(reloaded.log_format, LogFormat::Json);
+        assert_eq!(reloaded.sanitize, SanitizeMode::Escape);
+        assert!(!reloaded.keep_alive);
+        // The files of a FORMAT_MAP format can't be taken over
+        let (running, _) = from_file(r#"format_map = ["10.0.0.0/8:json"]"#);
+        let (new, _) = from_file(
+            r#"
+            log_format = "json"
+            format_map = ["10.0.0.0/8:json"]
+            "#,
+        );
+        assert_eq!(running.restart_only_changes(&new), ["LOG_FORMAT"]);
+        assert_eq!(running.reloaded(&new).log_format, LogFormat::Stamped);
+    }
+
+    #[test]
+    fn config_file_rejects_invalid_values() {
+        assert!(FileSettings::parse(r#"port = "abc""#).is_err());
+        assert!(FileSettings::parse(r#"shard_policy = "nope""#).is_err());
+        assert!(FileSettings::parse("ports = 8001").is_err());
+    }
+}
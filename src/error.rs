@@ -0,0 +1,81 @@
+use std::io;
+use std::net::SocketAddr;
+
+use thiserror::Error;
+
+use crate::human_readable_size;
+
+/// Errors that stop the server.
+#[derive(Debug, Error)]
+pub enum ScooperError {
+    #[error("{0}")]
+    Config(String),
+    #[error("Failed to bind {addr}: {source}")]
+    Bind { addr: SocketAddr, source: io::Error },
+    #[error("File size exceeds the limit of {}", human_readable_size(*.limit))]
+    LogFull { limit: usize },
+    #[error("Failed to write to the log, the disk is full: {0}")]
+    DiskFull(io::Error),
+    #[error("Gave up waiting for {open} open connections")]
+    ShutdownTimeout { open: u64 },
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+impl ScooperError {
+    /// Whether the error means nothing more can be written to the log.
+    pub fn is_log_full(&self) -> bool {
+        matches!(self, Self::LogFull { .. } | Self::DiskFull(_))
+    }
+
+    /// The process exit code for this error, config errors share the usage error code.
+    /// A clean shutdown exits with 0.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Self::Config(_) => 2,
+            Self::Bind { .. } => 3,
+            Self::DiskFull(_) => 4,
+            Self::ShutdownTimeout { .. } => 5,
+            Self::LogFull { .. } | Self::Io(_) => 1,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn io_error() -> io::Error {
+        io::Error::other("test")
+    }
+
+    #[test]
+    fn exit_codes_match_the_usage() {
+        let addr: SocketAddr = "127.0.0.1:5000".parse().unwrap();
+        let cases = [
+            (ScooperError::Config("bad".to_string()), 2),
+            (
+                ScooperError::Bind {
+                    addr,
+                    source: io_error(),
+                },
+                3,
+            ),
+            (ScooperError::LogFull { limit: 10 }, 1),
+            (ScooperError::DiskFull(io_error()), 4),
+            (ScooperError::ShutdownTimeout { open: 3 }, 5),
+            (ScooperError::Io(io_error()), 1),
+        ];
+        for (error, code) in cases {
+            assert_eq!(error.exit_code(), code, "{error:?}");
+        }
+    }
+
+    #[test]
+    fn only_a_full_log_or_disk_is_log_full() {
+        assert!(ScooperError::LogFull { limit: 10 }.is_log_full());
+        assert!(ScooperError::DiskFull(io_error()).is_log_full());
+        assert!(!ScooperError::Io(io_error()).is_log_full());
+        assert!(!ScooperError::ShutdownTimeout { open: 1 }.is_log_full());
+    }
+}
@@ -0,0 +1,83 @@
+//! Writing the log to a named pipe (`LOG_FIFO`, or a `LOG_FILE` that is one), to feed
+//! another process live.
+//!
+//! The pipe is opened without waiting for a reader. While there's none, writes fail with
+//! `NotConnected` and the entries are dropped. Every write tries to open the pipe again,
+//! so a reader that goes away and comes back picks up from the next entry.
+
+use std::io;
+use std::path::{Path, PathBuf};
+
+use tokio::io::AsyncWriteExt;
+use tokio::net::unix::pipe;
+
+#[derive(Debug)]
+pub struct FifoSink {
+    path: PathBuf,
+    sender: Option<pipe::Sender>,
+    warned: bool, // Whether the missing reader was reported since the last one left
+}
+
+/// Creates the named pipe at `path` unless it exists, in which case it has to be one.
+pub fn create(path: &Path) -> io::Result<()> {
+    if let Ok(metadata) = std::fs::metadata(path) {
+        use std::os::unix::fs::FileTypeExt;
+        return match metadata.file_type().is_fifo() {
+            true => Ok(()),
+            false => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} exists and isn't a FIFO", path.display()),
+            )),
+        };
+    }
+    let c_path = std::ffi::CString::new(path.as_os_str().as_encoded_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    // SAFETY: mkfifo only reads the nul-terminated path it's given
+    match unsafe { libc::mkfifo(c_path.as_ptr(), 0o644) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+impl FifoSink {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            sender: None,
+            warned: false,
+        }
+    }
+
+    fn no_reader(&mut self) -> io::Error {
+        if !self.warned {
+            eprintln!(
+                "No reader on FIFO {}, dropping entries until one opens it",
+                self.path.display()
+            );
+            self.warned = true;
+        }
+        io::Error::new(io::ErrorKind::NotConnected, "no reader on the FIFO")
+    }
+
+    pub async fn write_all(&mut self, data: &[u8]) -> io::Result<()> {
+        let sender = match &mut self.sender {
+            Some(sender) => sender,
+            None => match pipe::OpenOptions::new().open_sender(&self.path) {
+                Ok(sender) => {
+                    println!("Reader connected to FIFO {}", self.path.display());
+                    self.warned = false;
+                    self.sender.insert(sender)
+                }
+                Err(e) if e.raw_os_error() == Some(libc::ENXIO) => return Err(self.no_reader()),
+                Err(e) => return Err(e),
+            },
+        };
+        match sender.write_all(data).await {
+            Err(e) if e.kind() == io::ErrorKind::BrokenPipe => {
+                self.sender = None;
+                Err(self.no_reader())
+            }
+            result => result,
+        }
+    }
+}
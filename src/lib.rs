@@ -1,6 +1,22 @@
-use std::{env, time::SystemTime};
+use std::borrow::Cow;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, VecDeque};
+use std::fmt::Write;
+use std::future::Future;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::SystemTime;
+use std::time::{Duration, Instant};
 
-const SCALE_BYTES: [&'static str; 7] = ["B", "KB", "MB", "GB", "TB", "PB", "EB"];
+pub mod config;
+pub mod error;
+pub mod log_reader;
+
+const SCALE_BYTES: [&str; 7] = ["B", "KB", "MB", "GB", "TB", "PB", "EB"];
 
 pub fn now() -> u128 {
     SystemTime::now()
@@ -9,11 +25,1522 @@ pub fn now() -> u128 {
         .as_millis()
 }
 
-pub fn parsable_env_var<T: std::str::FromStr>(name: &str, default: T) -> T {
-    env::var(name)
+// Days since 1970-01-01 to a (year, month, day) civil date, see
+// https://howardhinnant.github.io/date_algorithms.html#civil_from_days
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+// The inverse of `civil_from_days`
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = year - i64::from(month <= 2);
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = (i64::from(month) + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + i64::from(day) - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+/// Parses a millisecond UNIX timestamp, or an ISO8601 date/time such as `2024-06-01`,
+/// `2024-06-01T12:30:00Z` or `2024-06-01T12:30:00.250+02:00` (UTC when no offset is given).
+pub fn parse_timestamp(s: &str) -> Option<u128> {
+    let s = s.trim();
+    if !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit()) {
+        return s.parse().ok();
+    }
+    let number = |s: &str| -> Option<i64> {
+        (!s.is_empty() && s.bytes().all(|b| b.is_ascii_digit()))
+            .then(|| s.parse().ok())
+            .flatten()
+    };
+    let (date, time) = s.split_once(['T', ' ']).unwrap_or((s, ""));
+    let mut date_parts = date.splitn(3, '-');
+    let year = number(date_parts.next()?)?;
+    let month = number(date_parts.next()?)?;
+    let day = number(date_parts.next()?)?;
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+
+    let (time, offset_secs) = if let Some(time) = time.strip_suffix(['Z', 'z']) {
+        (time, 0)
+    } else if let Some(i) = time.rfind(['+', '-']) {
+        let (time, offset) = time.split_at(i);
+        let sign = if offset.starts_with('-') { -1 } else { 1 };
+        let offset = &offset[1..];
+        let (hours, minutes) = match offset.split_once(':') {
+            Some(parts) => parts,
+            None if offset.len() == 4 => offset.split_at(2),
+            None => (offset, "0"),
+        };
+        (time, sign * (number(hours)? * 3600 + number(minutes)? * 60))
+    } else {
+        (time, 0)
+    };
+    let (time, millis) = match time.split_once('.') {
+        Some((time, fraction)) => {
+            let digits: String = fraction.chars().chain("000".chars()).take(3).collect();
+            (time, number(&digits)?)
+        }
+        None => (time, 0),
+    };
+    let mut time_parts = time.split(':').filter(|p| !p.is_empty());
+    let hours = time_parts.next().map(number).unwrap_or(Some(0))?;
+    let minutes = time_parts.next().map(number).unwrap_or(Some(0))?;
+    let seconds = time_parts.next().map(number).unwrap_or(Some(0))?;
+    if hours > 23 || minutes > 59 || seconds > 60 || time_parts.next().is_some() {
+        return None;
+    }
+
+    let days = days_from_civil(year, month as u32, day as u32);
+    let secs = days * 86_400 + hours * 3600 + minutes * 60 + seconds - offset_secs;
+    u128::try_from(secs * 1000 + millis).ok()
+}
+
+/// Quotes and escapes a string for use in JSON output.
+pub fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Formats a millisecond UNIX timestamp as an ISO8601 UTC string.
+pub fn format_timestamp(millis: u128) -> String {
+    let secs = (millis / 1000) as i64;
+    let (year, month, day) = civil_from_days(secs.div_euclid(86_400));
+    let time = secs.rem_euclid(86_400);
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+        time / 3600,
+        time % 3600 / 60,
+        time % 60,
+        millis % 1000
+    )
+}
+
+/// The path a log file is moved to when it's rotated, e.g. `messages.log.1718000000000`.
+pub fn archive_path(log_file: &Path, timestamp: u128) -> PathBuf {
+    let mut name = log_file.as_os_str().to_owned();
+    name.push(format!(".{timestamp}"));
+    PathBuf::from(name)
+}
+
+/// The file a log compressed with `algo` is written to, e.g. `messages.log.gz` for
+/// `messages.log` and gzip.
+pub fn compressed_path(log_file: &Path, algo: CompressAlgo) -> PathBuf {
+    let Some(extension) = algo.extension() else {
+        return log_file.to_path_buf();
+    };
+    if log_file.extension().is_some_and(|ext| ext == extension) {
+        return log_file.to_path_buf();
+    }
+    let mut name = log_file.as_os_str().to_owned();
+    name.push(format!(".{extension}"));
+    PathBuf::from(name)
+}
+
+/// The file a shard of a sharded log is written to, e.g. `messages.3.log` for `messages.log`.
+pub fn shard_path(log_file: &Path, shard: usize) -> PathBuf {
+    let stem = log_file.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match log_file.extension() {
+        Some(ext) => format!("{stem}.{shard}.{}", ext.to_string_lossy()),
+        None => format!("{stem}.{shard}"),
+    };
+    log_file.with_file_name(name)
+}
+
+/// Picks a shard for a client with a stable FNV-1a hash, so a client always maps to the
+/// same shard, across restarts too.
+pub fn shard_for(client: &str, shards: usize) -> usize {
+    let hash = client.bytes().fold(0xcbf2_9ce4_8422_2325_u64, |hash, b| {
+        (hash ^ u64::from(b)).wrapping_mul(0x0100_0000_01b3)
+    });
+    (hash % shards.max(1) as u64) as usize
+}
+
+/// How connections are assigned to the shards of `WRITE_SHARDS`. A connection stays on its
+/// shard until it closes, so its messages are in order within the shard. With
+/// `round_robin` and `least_loaded` a client that reconnects can land on another shard.
+/// `CLIENT_AFFINITY` places every client by the hash instead, whatever the policy is, which
+/// keeps each client's messages in order across reconnects but gives up their balancing.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ShardPolicy {
+    /// By a hash of the client IP, so a client always writes to the same shard
+    #[default]
+    Hash,
+    /// Each connection to the next shard
+    RoundRobin,
+    /// To the shard with the fewest open connections
+    LeastLoaded,
+}
+
+impl std::str::FromStr for ShardPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "hash" => Ok(Self::Hash),
+            "round_robin" => Ok(Self::RoundRobin),
+            "least_loaded" => Ok(Self::LeastLoaded),
+            _ => Err(format!("Unknown shard policy: {s}")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SanitizeMode {
+    #[default]
+    None,
+    Escape,
+    Strip,
+}
+
+impl std::str::FromStr for SanitizeMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "none" => Ok(Self::None),
+            "escape" => Ok(Self::Escape),
+            "strip" => Ok(Self::Strip),
+            _ => Err(format!("Unknown sanitize mode: {s}")),
+        }
+    }
+}
+
+/// Whether each payload is preceded by a `$$$...$$$` metadata stamp.
+/// Without stamps the log is just the payloads (and separators) concatenated,
+/// which the `read`, `search` and `verify` commands can't parse.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum StampMode {
+    #[default]
+    Full,
+    None,
+}
+
+impl std::str::FromStr for StampMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "full" => Ok(Self::Full),
+            "none" => Ok(Self::None),
+            _ => Err(format!("Unknown stamp mode: {s}")),
+        }
+    }
+}
+
+/// The largest message a `lines` or `length` framed connection may send.
+pub const MAX_FRAME_LEN: usize = 16 * 1024 * 1024;
+const LENGTH_PREFIX_LEN: usize = 4;
+
+/// How messages are delimited on a connection.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Framing {
+    /// Every read from the socket is a message
+    #[default]
+    Raw,
+    /// Messages are separated by newlines (`\n` or `\r\n`), an empty line is an empty message
+    Lines,
+    /// Every message starts with its length as a 4-byte big-endian integer
+    Length,
+    /// Guessed per connection from the first bytes it sends, see `detect_framing`
+    Auto,
+}
+
+impl std::str::FromStr for Framing {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "raw" => Ok(Self::Raw),
+            "lines" => Ok(Self::Lines),
+            "length" => Ok(Self::Length),
+            "auto" => Ok(Self::Auto),
+            _ => Err(format!("Unknown framing: {s}")),
+        }
+    }
+}
+
+/// Guesses the framing from the first bytes of a connection (as far as they've arrived):
+///
+/// - A 4-byte prefix that's a plausible length (1 to `MAX_FRAME_LEN`) means `length`.
+///   Since the limit is 16 MB the first byte has to be 0 or 1, which text never starts with.
+/// - Otherwise a newline anywhere in the bytes means `lines`.
+/// - Anything else is `raw`.
+///
+/// Only what's in the first segment is looked at, so a line client whose first line is
+/// split across segments is taken as raw, and a binary raw client could pass for `length`.
+pub fn detect_framing(peeked: &[u8]) -> Framing {
+    if let Some(prefix) = peeked.first_chunk::<LENGTH_PREFIX_LEN>() {
+        let len = u32::from_be_bytes(*prefix) as usize;
+        if (1..=MAX_FRAME_LEN).contains(&len) {
+            return Framing::Length;
+        }
+    }
+    match peeked.contains(&b'\n') {
+        true => Framing::Lines,
+        false => Framing::Raw,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FrameError {
+    /// A message is longer than `MAX_FRAME_LEN`
+    Oversize(usize),
+    /// The connection closed in the middle of a length-prefixed message
+    Truncated,
+}
+
+impl std::fmt::Display for FrameError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Oversize(len) => write!(
+                f,
+                "message of {} exceeds the limit of {}",
+                human_readable_size(*len),
+                human_readable_size(MAX_FRAME_LEN)
+            ),
+            Self::Truncated => write!(f, "connection closed in the middle of a message"),
+        }
+    }
+}
+
+/// Takes the complete messages off the front of `pending`, leaving any partial one.
+/// At `eof`, a partial raw or line message is taken as well.
+pub fn take_frames(
+    framing: Framing,
+    pending: &mut Vec<u8>,
+    eof: bool,
+) -> Result<Vec<Vec<u8>>, FrameError> {
+    let mut frames = Vec::new();
+    match framing {
+        Framing::Raw | Framing::Auto => {
+            if !pending.is_empty() {
+                frames.push(std::mem::take(pending));
+            }
+        }
+        Framing::Lines => {
+            let mut start = 0;
+            while let Some(i) = pending[start..].iter().position(|&b| b == b'\n') {
+                let line = &pending[start..start + i];
+                let line = line.strip_suffix(b"\r").unwrap_or(line);
+                if line.len() > MAX_FRAME_LEN {
+                    return Err(FrameError::Oversize(line.len()));
+                }
+                frames.push(line.to_vec());
+                start += i + 1;
+            }
+            pending.drain(..start);
+            if pending.len() > MAX_FRAME_LEN {
+                return Err(FrameError::Oversize(pending.len()));
+            }
+            if eof && !pending.is_empty() {
+                frames.push(std::mem::take(pending));
+            }
+        }
+        Framing::Length => {
+            let mut start = 0;
+            while let Some(prefix) = pending[start..].first_chunk::<LENGTH_PREFIX_LEN>() {
+                let len = u32::from_be_bytes(*prefix) as usize;
+                if len > MAX_FRAME_LEN {
+                    return Err(FrameError::Oversize(len));
+                }
+                let end = start + LENGTH_PREFIX_LEN + len;
+                if pending.len() < end {
+                    break;
+                }
+                frames.push(pending[start + LENGTH_PREFIX_LEN..end].to_vec());
+                start = end;
+            }
+            pending.drain(..start);
+            if eof && !pending.is_empty() {
+                return Err(FrameError::Truncated);
+            }
+        }
+    }
+    Ok(frames)
+}
+
+/// The longest PROXY protocol v1 header, including its `\r\n`.
+pub const PROXY_V1_MAX_LEN: usize = 107;
+/// The first 12 bytes of a PROXY protocol v2 header.
+pub const PROXY_V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Parses a complete PROXY protocol header, v1 (text) or v2 (binary), into the address of
+/// the client behind the proxy. That's `None` when the proxy doesn't tell, e.g. for its own
+/// health checks (v1 `UNKNOWN`, v2 `LOCAL`).
+pub fn parse_proxy_header(header: &[u8]) -> Result<Option<SocketAddr>, String> {
+    if let Some(v2) = header.strip_prefix(&PROXY_V2_SIGNATURE) {
+        let [version_command, family, _, _, addresses @ ..] = v2 else {
+            return Err("truncated PROXY v2 header".to_string());
+        };
+        if version_command >> 4 != 2 {
+            return Err(format!(
+                "unsupported PROXY version {}",
+                version_command >> 4
+            ));
+        }
+        match version_command & 0x0f {
+            0 => return Ok(None), // LOCAL
+            1 => {}
+            command => return Err(format!("unknown PROXY v2 command {command}")),
+        }
+        return match (family >> 4, addresses) {
+            (1, [a, b, c, d, _, _, _, _, p1, p2, ..]) => Ok(Some(SocketAddr::from((
+                [*a, *b, *c, *d],
+                u16::from_be_bytes([*p1, *p2]),
+            )))),
+            (2, addresses) if addresses.len() >= 36 => {
+                let ip: [u8; 16] = addresses[..16].try_into().unwrap_or_default();
+                let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+                Ok(Some(SocketAddr::from((ip, port))))
+            }
+            (1 | 2, _) => Err("truncated PROXY v2 addresses".to_string()),
+            _ => Ok(None), // Unspecified or UNIX sockets, there's no client address to use
+        };
+    }
+    let line = std::str::from_utf8(header)
         .ok()
-        .and_then(|v| v.parse().ok())
-        .unwrap_or(default)
+        .and_then(|line| line.strip_suffix("\r\n"))
+        .ok_or("invalid PROXY v1 header")?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4" | "TCP6", source, _, port, _] => {
+            let ip: IpAddr = source
+                .parse()
+                .map_err(|_| format!("invalid PROXY source address {source:?}"))?;
+            let port: u16 = port
+                .parse()
+                .map_err(|_| format!("invalid PROXY source port {port:?}"))?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(format!("invalid PROXY v1 header {line:?}")),
+    }
+}
+
+/// How clients compress what they send, it's decompressed before it's framed and logged.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum IngestCompress {
+    #[default]
+    None,
+    /// Each connection is a gzip stream (or several, back to back), e.g. `UPSTREAM_COMPRESS`
+    Gzip,
+}
+
+impl std::str::FromStr for IngestCompress {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "none" => Ok(Self::None),
+            "gzip" => Ok(Self::Gzip),
+            _ => Err(format!("Unknown ingest compression: {s}")),
+        }
+    }
+}
+
+/// The codec of `COMPRESS_LOG` and of rotated archives, `none` leaves both uncompressed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CompressAlgo {
+    #[default]
+    Gzip,
+    Zstd,
+    None,
+}
+
+impl CompressAlgo {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Gzip => "gzip",
+            Self::Zstd => "zstd",
+            Self::None => "none",
+        }
+    }
+
+    /// The extension of the files it writes, see `compressed_path`.
+    pub fn extension(self) -> Option<&'static str> {
+        match self {
+            Self::Gzip => Some("gz"),
+            Self::Zstd => Some("zst"),
+            Self::None => None,
+        }
+    }
+
+    /// The `COMPRESS_LEVEL`s this codec accepts.
+    pub fn levels(self) -> std::ops::RangeInclusive<u32> {
+        match self {
+            Self::Gzip => 0..=9,
+            Self::Zstd => 1..=22,
+            Self::None => 0..=0,
+        }
+    }
+
+    pub fn default_level(self) -> u32 {
+        match self {
+            Self::Gzip => 6,
+            Self::Zstd => 3,
+            Self::None => 0,
+        }
+    }
+}
+
+impl std::str::FromStr for CompressAlgo {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "gzip" => Ok(Self::Gzip),
+            "zstd" => Ok(Self::Zstd),
+            "none" => Ok(Self::None),
+            _ => Err(format!("Unknown compression codec: {s}")),
+        }
+    }
+}
+
+/// What happens to an empty message, i.e. an empty line or a zero length frame. A connection
+/// that closes without sending anything isn't a message.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EmptyMessage {
+    #[default]
+    Ignore,
+    /// Log it as a record with `len` 0
+    Log,
+    /// Close the connection, e.g. for clients that should never send one
+    Close,
+}
+
+impl std::str::FromStr for EmptyMessage {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "ignore" => Ok(Self::Ignore),
+            "log" => Ok(Self::Log),
+            "close" => Ok(Self::Close),
+            _ => Err(format!("Unknown empty message policy: {s}")),
+        }
+    }
+}
+
+impl EmptyMessage {
+    /// Applies the policy to frames from `take_frames`. Returns `true` if the connection has
+    /// to be closed once the frames that are left are logged.
+    pub fn apply(&self, frames: &mut Vec<Vec<u8>>) -> bool {
+        match self {
+            Self::Ignore => {
+                frames.retain(|frame| !frame.is_empty());
+                false
+            }
+            Self::Log => false,
+            Self::Close => match frames.iter().position(Vec::is_empty) {
+                Some(empty) => {
+                    frames.truncate(empty);
+                    true
+                }
+                None => false,
+            },
+        }
+    }
+}
+
+/// A checksum that clients append to every framed message, checked before it's logged.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum IngestChecksum {
+    #[default]
+    None,
+    /// The CRC-32 (as in gzip) of the payload: 4 big-endian bytes with `length` framing,
+    /// or 8 hex digits with `lines` framing, since raw bytes could contain a newline
+    Crc32,
+}
+
+impl std::str::FromStr for IngestChecksum {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "none" => Ok(Self::None),
+            "crc32" => Ok(Self::Crc32),
+            _ => Err(format!("Unknown ingest checksum: {s}")),
+        }
+    }
+}
+
+/// Splits the checksum off a frame, returning the payload if the checksum matches.
+/// Raw frames have no clear end to take a checksum from, so they're returned as is.
+pub fn strip_checksum(checksum: IngestChecksum, framing: Framing, frame: &[u8]) -> Option<&[u8]> {
+    if checksum == IngestChecksum::None {
+        return Some(frame);
+    }
+    let (payload, expected) = match framing {
+        Framing::Length => {
+            let (payload, crc) = frame.split_last_chunk::<4>()?;
+            (payload, u32::from_be_bytes(*crc))
+        }
+        Framing::Lines => {
+            let (payload, crc) = frame.split_last_chunk::<8>()?;
+            if !crc.iter().all(u8::is_ascii_hexdigit) {
+                return None;
+            }
+            let crc = std::str::from_utf8(crc).ok()?;
+            (payload, u32::from_str_radix(crc, 16).ok()?)
+        }
+        Framing::Raw | Framing::Auto => return Some(frame),
+    };
+    let mut crc = flate2::Crc::new();
+    crc.update(payload);
+    (crc.sum() == expected).then_some(payload)
+}
+
+/// Replaces `\n`, `\r`, `\t`, `\0` and `\\` with the characters they stand for,
+/// so separators like newlines can be given in env vars.
+pub fn unescape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => out.push('\n'),
+            Some('r') => out.push('\r'),
+            Some('t') => out.push('\t'),
+            Some('0') => out.push('\0'),
+            Some(other) => out.push(other),
+            None => out.push('\\'),
+        }
+    }
+    out
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OpenMode {
+    #[default]
+    Append,
+    Truncate,
+    New,
+}
+
+impl std::str::FromStr for OpenMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "append" => Ok(Self::Append),
+            "truncate" => Ok(Self::Truncate),
+            "new" => Ok(Self::New),
+            _ => Err(format!("Unknown open mode: {s}")),
+        }
+    }
+}
+
+/// What the server does once the log is full (`MAX_FILE_SIZE` or a full disk).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OnFull {
+    /// Shut down right away, dropping open connections
+    #[default]
+    Exit,
+    /// Stop accepting connections, discard what the open ones send, then shut down
+    Drain,
+    /// Archive the full log files and carry on with new ones
+    Rotate,
+}
+
+impl std::str::FromStr for OnFull {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "exit" => Ok(Self::Exit),
+            "drain" => Ok(Self::Drain),
+            "rotate" => Ok(Self::Rotate),
+            _ => Err(format!("Unknown full log behavior: {s}")),
+        }
+    }
+}
+
+/// Escapes or strips control characters (other than newlines) from UTF-8 payloads, `strip`
+/// keeps the ones in `delimiter` too, i.e. the `SEPARATOR` between payloads. Payloads that
+/// aren't valid UTF-8 are returned untouched.
+pub fn sanitize_payload<'a>(
+    payload: &'a [u8],
+    mode: SanitizeMode,
+    delimiter: &str,
+) -> Cow<'a, [u8]> {
+    let text = match std::str::from_utf8(payload) {
+        Ok(text) if mode != SanitizeMode::None => text,
+        _ => return Cow::Borrowed(payload),
+    };
+    let is_unsafe = |c: char| {
+        c.is_control() && c != '\n' && !(mode == SanitizeMode::Strip && delimiter.contains(c))
+    };
+    if !text.chars().any(is_unsafe) {
+        return Cow::Borrowed(payload);
+    }
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match (is_unsafe(c), mode) {
+            (false, _) => out.push(c),
+            (true, SanitizeMode::Escape) => {
+                let _ = write!(out, "\\x{:02X}", c as u32);
+            }
+            (true, _) => {}
+        }
+    }
+    Cow::Owned(out.into_bytes())
+}
+
+/// The client field of records the server writes about itself, like run markers.
+pub const SERVER_CLIENT: &str = "server";
+pub const RUN_START: &str = "START";
+pub const RUN_STOP: &str = "STOP";
+
+/// Where the newline separating records goes: before each stamp (`\n$$$...$$$\n<payload>`),
+/// or after each payload (`$$$...$$$\n<payload>\n`) so files end with a newline. Either
+/// way files start with a stamp, the first record of a file has no leading newline. The
+/// reader accepts both, even mixed in one file.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RecordSeparator {
+    #[default]
+    Leading,
+    Trailing,
+}
+
+impl RecordSeparator {
+    /// Bytes written before the stamp, except in the first record of a file.
+    pub fn leader(&self) -> &'static [u8] {
+        match self {
+            Self::Leading => b"\n",
+            Self::Trailing => b"",
+        }
+    }
+
+    /// Bytes written after the payload.
+    pub fn trailer(&self) -> &'static [u8] {
+        match self {
+            Self::Leading => b"",
+            Self::Trailing => b"\n",
+        }
+    }
+}
+
+impl std::str::FromStr for RecordSeparator {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "leading" => Ok(Self::Leading),
+            "trailing" => Ok(Self::Trailing),
+            _ => Err(format!("Unknown record separator: {s}")),
+        }
+    }
+}
+
+/// Whether payloads that were logged recently are written again.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DedupMode {
+    #[default]
+    Off,
+    /// A payload seen within the dedup window, from any client, is logged as a
+    /// `dup=<seq>` record pointing at the `seq=<seq>` record that holds it
+    Global,
+}
+
+impl std::str::FromStr for DedupMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "off" => Ok(Self::Off),
+            "global" => Ok(Self::Global),
+            _ => Err(format!("Unknown dedup mode: {s}")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dedup {
+    /// Not seen recently, log it with this sequence number
+    New(u64),
+    /// Seen recently, logged with this sequence number
+    DuplicateOf(u64),
+}
+
+/// The last `capacity` distinct payloads, by hash, up to `max_bytes` of them in total. The
+/// payloads are kept too, so a hash collision is never mistaken for a duplicate.
+#[derive(Debug)]
+pub struct DedupCache {
+    capacity: usize,
+    max_bytes: usize,
+    bytes: usize, // Of all the payloads in `entries`
+    next_seq: u64,
+    entries: HashMap<u64, (u64, Vec<u8>)>,
+    order: VecDeque<(u64, u64)>, // (hash, seq) from oldest to newest
+}
+
+impl DedupCache {
+    pub fn new(capacity: usize, max_bytes: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            max_bytes,
+            bytes: 0,
+            next_seq: 1,
+            entries: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    fn hash(payload: &[u8]) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        payload.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// The sequence number of the record holding `payload`, if it was logged recently.
+    pub fn find(&self, payload: &[u8]) -> Option<u64> {
+        match self.entries.get(&Self::hash(payload)) {
+            Some((seq, seen)) if seen == payload => Some(*seq),
+            _ => None,
+        }
+    }
+
+    /// Remembers a payload that's being logged, and returns its sequence number. A payload
+    /// bigger than `max_bytes` gets one but isn't remembered.
+    pub fn insert(&mut self, payload: &[u8]) -> u64 {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        if payload.len() > self.max_bytes {
+            return seq;
+        }
+        let hash = Self::hash(payload);
+        if let Some((_, replaced)) = self.entries.insert(hash, (seq, payload.to_vec())) {
+            self.bytes -= replaced.len();
+        }
+        self.bytes += payload.len();
+        self.order.push_back((hash, seq));
+        while self.order.len() > self.capacity || self.bytes > self.max_bytes {
+            let Some((oldest, oldest_seq)) = self.order.pop_front() else {
+                break;
+            };
+            // A colliding payload may have replaced it already
+            if self
+                .entries
+                .get(&oldest)
+                .is_some_and(|(seq, _)| *seq == oldest_seq)
+            {
+                if let Some((_, evicted)) = self.entries.remove(&oldest) {
+                    self.bytes -= evicted.len();
+                }
+            }
+        }
+        seq
+    }
+}
+
+const CLIENT_FIELD_ELLIPSIS: &str = "...";
+
+/// Replaces the characters that would break a stamp, `$` and control characters, with `_`.
+pub fn stamp_safe(field: &str) -> String {
+    field
+        .chars()
+        .map(|c| if c == '$' || c.is_control() { '_' } else { c })
+        .collect()
+}
+
+/// The client field of a stamp: `identity@ip:port` when there's a known identity (the
+/// `REVERSE_DNS` host name), `ip:port` otherwise. With a `max_len` (0 is no limit), only the
+/// identity is shortened so the address is always there. `$` and control characters in the
+/// identity are replaced so it can't break the stamp.
+pub fn client_field(identity: Option<&str>, addr: &SocketAddr, max_len: usize) -> String {
+    let addr = addr.to_string();
+    let Some(identity) = identity.filter(|identity| !identity.is_empty()) else {
+        return addr;
+    };
+    let identity = stamp_safe(identity);
+    let room = max_len.saturating_sub(addr.len() + 1);
+    if max_len == 0 || identity.len() <= room {
+        return format!("{identity}@{addr}");
+    }
+    let mut kept = room.saturating_sub(CLIENT_FIELD_ELLIPSIS.len());
+    while !identity.is_char_boundary(kept) {
+        kept -= 1;
+    }
+    format!("{}{CLIENT_FIELD_ELLIPSIS}@{addr}", &identity[..kept])
+}
+
+/// Builds the stamp line written before a payload, `extra` fields go after `len`.
+pub fn format_stamp(
+    timestamp: u128,
+    client: &str,
+    len: usize,
+    extra: &[&str],
+    separator: RecordSeparator,
+) -> String {
+    let mut stamp = match separator {
+        RecordSeparator::Leading => format!("\n$$${timestamp}$$${client}$$${len}$$$"),
+        RecordSeparator::Trailing => format!("$$${timestamp}$$${client}$$${len}$$$"),
+    };
+    for field in extra {
+        stamp.push_str(field);
+        stamp.push_str("$$$");
+    }
+    stamp.push('\n');
+    stamp
+}
+
+/// An empty record marking where a server run starts or stops, e.g. `$$$ts$$$server$$$0$$$START$$$`.
+/// The kind is always the first extra field, the `INSTANCE_TAG` field can follow it.
+pub fn run_marker(kind: &str, instance: Option<&str>, formatter: &dyn Formatter) -> Vec<u8> {
+    let instance = instance.map(instance_field);
+    let extra: Vec<&str> = [Some(kind), instance.as_deref()]
+        .into_iter()
+        .flatten()
+        .collect();
+    let marker = EntryMeta {
+        timestamp: now(),
+        client: SERVER_CLIENT,
+        extra: &extra,
+    };
+    let mut out = Vec::new();
+    formatter.encode(&marker, &[], &mut out);
+    out
+}
+
+/// How the records of a log file are written.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    /// `$$$ts$$$client$$$len$$$` stamps followed by the raw payload
+    #[default]
+    Stamped,
+    /// One JSON object per line, with `payload_hex` instead of `payload` for binary payloads
+    Json,
+    /// Length prefixed fields: a u64 timestamp, then the client (u16 length), the extra
+    /// fields joined by `$$$` (u16 length) and the payload (u32 length), all big endian
+    Binary,
+}
+
+impl LogFormat {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Stamped => "stamped",
+            Self::Json => "json",
+            Self::Binary => "binary",
+        }
+    }
+
+    /// The formatter of this format. `STAMP=none` only applies to `stamped`, the other
+    /// formats always carry their metadata.
+    pub fn formatter(
+        self,
+        stamp: StampMode,
+        record_separator: RecordSeparator,
+        separator: &[u8],
+    ) -> Arc<dyn Formatter> {
+        match (self, stamp) {
+            (Self::Stamped, StampMode::Full) => Arc::new(StampedFormatter {
+                separator: record_separator,
+            }),
+            (Self::Stamped, StampMode::None) => Arc::new(RawFormatter {
+                separator: separator.into(),
+            }),
+            (Self::Json, _) => Arc::new(JsonFormatter),
+            (Self::Binary, _) => Arc::new(BinaryFormatter),
+        }
+    }
+}
+
+impl std::str::FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "stamped" => Ok(Self::Stamped),
+            "json" => Ok(Self::Json),
+            "binary" => Ok(Self::Binary),
+            _ => Err(format!("Unknown log format: {s}")),
+        }
+    }
+}
+
+/// The file a log format other than `stamped` is written to, e.g. `messages.json.log`
+/// for `messages.log`, so every file holds a single format.
+pub fn format_path(log_file: &Path, format: LogFormat) -> PathBuf {
+    if format == LogFormat::Stamped {
+        return log_file.to_path_buf();
+    }
+    let stem = log_file.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match log_file.extension() {
+        Some(ext) => format!("{stem}.{}.{}", format.as_str(), ext.to_string_lossy()),
+        None => format!("{stem}.{}", format.as_str()),
+    };
+    log_file.with_file_name(name)
+}
+
+/// An IP address range, e.g. `10.0.0.0/8`. A bare address matches only itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl std::str::FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|_| format!("Invalid address in {s:?}"))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse()
+                .ok()
+                .filter(|&prefix| prefix <= max)
+                .ok_or_else(|| format!("Invalid prefix length in {s:?}"))?,
+            None => max,
+        };
+        Ok(Self {
+            addr: addr.to_canonical(),
+            prefix,
+        })
+    }
+}
+
+/// A `FORMAT_MAP` entry, e.g. `10.0.0.0/8:json`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FormatRule {
+    pub clients: Cidr,
+    pub format: LogFormat,
+}
+
+impl std::str::FromStr for FormatRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // IPv6 ranges have colons of their own, the format is after the last one
+        let (clients, format) = s
+            .rsplit_once(':')
+            .ok_or_else(|| format!("Expected <range>:<format>, got {s:?}"))?;
+        Ok(Self {
+            clients: clients.trim().parse()?,
+            format: format.trim().parse()?,
+        })
+    }
+}
+
+/// The format of the first rule matching `ip`, or `default` if none does.
+pub fn format_for(rules: &[FormatRule], ip: IpAddr, default: LogFormat) -> LogFormat {
+    rules
+        .iter()
+        .find(|rule| rule.clients.contains(ip))
+        .map_or(default, |rule| rule.format)
+}
+
+/// Everything that's logged about a message besides its payload.
+#[derive(Debug, Clone, Copy)]
+pub struct EntryMeta<'a> {
+    pub timestamp: u128,
+    pub client: &'a str,
+    pub extra: &'a [&'a str],
+}
+
+/// Encodes log entries in one output format.
+pub trait Formatter: std::fmt::Debug + Send + Sync {
+    /// Appends the entry of `payload` to `out`.
+    fn encode(&self, entry: &EntryMeta, payload: &[u8], out: &mut Vec<u8>);
+
+    /// What every entry starts with, the writer leaves it out of the first one in a file.
+    fn leader(&self) -> &'static [u8] {
+        b""
+    }
+}
+
+/// `LOG_FORMAT=stamped`, a `$$$ts$$$client$$$len$$$` stamp followed by the raw payload.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StampedFormatter {
+    pub separator: RecordSeparator,
+}
+
+impl Formatter for StampedFormatter {
+    fn encode(&self, entry: &EntryMeta, payload: &[u8], out: &mut Vec<u8>) {
+        let stamp = format_stamp(
+            entry.timestamp,
+            entry.client,
+            payload.len(),
+            entry.extra,
+            self.separator,
+        );
+        out.extend_from_slice(stamp.as_bytes());
+        out.extend_from_slice(payload);
+        out.extend_from_slice(self.separator.trailer());
+    }
+
+    fn leader(&self) -> &'static [u8] {
+        self.separator.leader()
+    }
+}
+
+/// `STAMP=none`, only the payloads, each followed by `SEPARATOR`.
+#[derive(Debug, Clone, Default)]
+pub struct RawFormatter {
+    pub separator: Arc<[u8]>,
+}
+
+impl Formatter for RawFormatter {
+    fn encode(&self, _entry: &EntryMeta, payload: &[u8], out: &mut Vec<u8>) {
+        out.extend_from_slice(payload);
+        out.extend_from_slice(&self.separator);
+    }
+}
+
+/// `LOG_FORMAT=json`, see [`LogFormat::Json`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonFormatter;
+
+impl Formatter for JsonFormatter {
+    fn encode(&self, entry: &EntryMeta, payload: &[u8], out: &mut Vec<u8>) {
+        let mut line = format!(
+            "{{\"timestamp\":{},\"client\":{},\"len\":{}",
+            entry.timestamp,
+            json_string(entry.client),
+            payload.len()
+        );
+        if !entry.extra.is_empty() {
+            let extra: Vec<String> = entry.extra.iter().map(|e| json_string(e)).collect();
+            let _ = write!(line, ",\"extra\":[{}]", extra.join(","));
+        }
+        match std::str::from_utf8(payload) {
+            Ok(text) => {
+                let _ = write!(line, ",\"payload\":{}", json_string(text));
+            }
+            Err(_) => {
+                line.push_str(",\"payload_hex\":\"");
+                for b in payload {
+                    let _ = write!(line, "{b:02x}");
+                }
+                line.push('"');
+            }
+        }
+        line.push_str("}\n");
+        out.extend_from_slice(line.as_bytes());
+    }
+}
+
+/// `LOG_FORMAT=binary`, see [`LogFormat::Binary`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BinaryFormatter;
+
+impl Formatter for BinaryFormatter {
+    fn encode(&self, entry: &EntryMeta, payload: &[u8], out: &mut Vec<u8>) {
+        let extra = entry.extra.join("$$$");
+        let client = &entry.client.as_bytes()[..entry.client.len().min(u16::MAX as usize)];
+        let extra = &extra.as_bytes()[..extra.len().min(u16::MAX as usize)];
+        out.reserve(16 + client.len() + extra.len() + payload.len());
+        out.extend_from_slice(&(entry.timestamp as u64).to_be_bytes());
+        out.extend_from_slice(&(client.len() as u16).to_be_bytes());
+        out.extend_from_slice(client);
+        out.extend_from_slice(&(extra.len() as u16).to_be_bytes());
+        out.extend_from_slice(extra);
+        out.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        out.extend_from_slice(payload);
+    }
+}
+
+/// Classifies a payload as `text` (valid UTF-8) or `bin`. Truncated multi-byte sequences
+/// count as binary.
+pub fn content_tag(payload: &[u8]) -> &'static str {
+    match std::str::from_utf8(payload) {
+        Ok(_) => "text",
+        Err(_) => "bin",
+    }
+}
+
+/// The extra field naming the scooper instance that logged a record, e.g. `instance=web1`.
+pub fn instance_field(tag: &str) -> String {
+    format!("instance={tag}")
+}
+
+/// How a client's message is turned into a log entry.
+#[derive(Debug, Clone)]
+pub struct EntryOptions {
+    pub formatter: Arc<dyn Formatter>,
+    pub instance: Option<Arc<str>>, // `INSTANCE_TAG`, already made stamp safe
+    pub sanitize: SanitizeMode,
+    pub delimiter: Arc<str>, // `SEPARATOR`, see `sanitize_payload`
+    pub human_size: bool,
+    pub tag_content: bool,
+}
+
+impl Default for EntryOptions {
+    fn default() -> Self {
+        Self {
+            formatter: Arc::new(StampedFormatter::default()),
+            instance: None,
+            sanitize: SanitizeMode::default(),
+            delimiter: Arc::from(""),
+            human_size: false,
+            tag_content: false,
+        }
+    }
+}
+
+/// Appends the log entry of an already sanitized payload from `client` to `out`. A
+/// duplicate, see [`DedupCache`], is logged without its payload and points back at the first
+/// one instead.
+pub fn encode_entry(
+    client: &str,
+    payload: &[u8],
+    dedup: Option<Dedup>,
+    options: &EntryOptions,
+    out: &mut Vec<u8>,
+) {
+    let logged = match dedup {
+        Some(Dedup::DuplicateOf(_)) => &[],
+        _ => payload,
+    };
+    let mut extra = Vec::new();
+    match dedup {
+        Some(Dedup::DuplicateOf(seq)) => extra.push(format!("dup={seq}")),
+        new => {
+            if options.human_size {
+                extra.push(format!("({})", human_readable_size(logged.len())));
+            }
+            if options.tag_content {
+                extra.push(content_tag(logged).to_string());
+            }
+            if let Some(Dedup::New(seq)) = new {
+                extra.push(format!("seq={seq}"));
+            }
+        }
+    }
+    if let Some(instance) = &options.instance {
+        extra.push(instance_field(instance));
+    }
+    let extra: Vec<&str> = extra.iter().map(String::as_str).collect();
+    let entry = EntryMeta {
+        timestamp: now(),
+        client,
+        extra: &extra,
+    };
+    options.formatter.encode(&entry, logged, out);
+}
+
+/// A received message, sanitized and looked up in the recent payloads of `DEDUP=global`.
+pub struct Message<'a> {
+    pub payload: Cow<'a, [u8]>,
+    duplicate_of: Option<u64>,
+}
+
+impl<'a> Message<'a> {
+    pub fn new(
+        message: &'a [u8],
+        dedup: Option<&Mutex<DedupCache>>,
+        options: &EntryOptions,
+    ) -> Self {
+        let payload = sanitize_payload(message, options.sanitize, &options.delimiter);
+        let duplicate_of = dedup.and_then(|cache| {
+            let cache = cache.lock().unwrap_or_else(PoisonError::into_inner);
+            cache.find(&payload)
+        });
+        Self {
+            payload,
+            duplicate_of,
+        }
+    }
+
+    /// The payload bytes that go in the log, a duplicate is logged without its payload.
+    pub fn logged_len(&self) -> usize {
+        match self.duplicate_of {
+            Some(_) => 0,
+            None => self.payload.len(),
+        }
+    }
+}
+
+/// Appends the entry of a message from `client` to `out` the way the server logs it, once
+/// there's room for its `logged_len`. A new payload is numbered in `dedup` only then, so a
+/// dropped message leaves nothing for a duplicate to point at.
+pub fn encode_message(
+    client: &str,
+    message: &Message,
+    dedup: Option<&Mutex<DedupCache>>,
+    options: &EntryOptions,
+    out: &mut Vec<u8>,
+) -> Option<Dedup> {
+    let dedup = dedup.map(|cache| match message.duplicate_of {
+        Some(seq) => Dedup::DuplicateOf(seq),
+        None => {
+            let mut cache = cache.lock().unwrap_or_else(PoisonError::into_inner);
+            Dedup::New(cache.insert(&message.payload))
+        }
+    });
+    encode_entry(client, &message.payload, dedup, options, out);
+    dedup
+}
+
+/// Where `persist` writes batches of formatted entries, the server's log files are one.
+pub trait EntrySink: Send {
+    /// Writes one or more formatted entries, `received` holds the receive time of each and
+    /// `payload_bytes` their total payload size.
+    fn write_entries(
+        &mut self,
+        data: &[u8],
+        received: &[Instant],
+        payload_bytes: usize,
+    ) -> impl Future<Output = io::Result<()>> + Send;
+
+    /// Makes room for `n` more entries before they're written, returning the bytes that no
+    /// longer count against the size of the log.
+    fn make_room(&mut self, _n: usize) -> impl Future<Output = io::Result<usize>> + Send {
+        std::future::ready(Ok(0))
+    }
+
+    /// A compressed sink counts the bytes it writes with `take_written`, instead of the
+    /// payload bytes it's given.
+    fn is_compressed(&self) -> bool {
+        false
+    }
+
+    /// The compressed bytes written since the last call.
+    fn take_written(&mut self) -> usize {
+        0
+    }
+}
+
+/// Keeps everything in memory, for benchmarks and embedders that do their own writing.
+impl EntrySink for Vec<u8> {
+    fn write_entries(
+        &mut self,
+        data: &[u8],
+        _received: &[Instant],
+        _payload_bytes: usize,
+    ) -> impl Future<Output = io::Result<()>> + Send {
+        self.extend_from_slice(data);
+        std::future::ready(Ok(()))
+    }
+}
+
+/// Whether `n` more bytes fit in the log. They're counted right away if they do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WriteOutcome {
+    Written,
+    WouldExceed,
+}
+
+pub async fn increment_bytes_counter(
+    bytes_counter: &tokio::sync::Mutex<usize>,
+    n: usize,
+    max_size: usize,
+) -> WriteOutcome {
+    let mut bytes_guard = bytes_counter.lock().await;
+    if *bytes_guard + n > max_size {
+        return WriteOutcome::WouldExceed;
+    }
+    *bytes_guard += n;
+    WriteOutcome::Written
+    // bytes_guard goes out of scope and releases the lock
+}
+
+/// A connection's formatted entries that haven't been written to the log yet.
+#[derive(Debug, Default)]
+pub struct EntryBatch {
+    data: Vec<u8>,
+    received: Vec<Instant>,
+    sizes: Vec<usize>,
+}
+
+impl EntryBatch {
+    /// Adds the entry that `encode` appends to the batch's data.
+    pub fn push<T>(
+        &mut self,
+        payload_size: usize,
+        received: Instant,
+        encode: impl FnOnce(&mut Vec<u8>) -> T,
+    ) -> T {
+        let encoded = encode(&mut self.data);
+        self.received.push(received);
+        self.sizes.push(payload_size);
+        encoded
+    }
+
+    /// When the batch has to be written, even if no more messages arrive.
+    pub fn deadline(&self, buffer_time: Duration) -> Option<Instant> {
+        self.received.first().map(|&first| first + buffer_time)
+    }
+
+    /// The number of entries in the batch.
+    pub fn len(&self) -> usize {
+        self.received.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.received.is_empty()
+    }
+
+    /// The payload bytes of all the entries.
+    pub fn payload_bytes(&self) -> usize {
+        self.sizes.iter().sum()
+    }
+
+    fn clear(&mut self) {
+        self.data.clear();
+        self.received.clear();
+        self.sizes.clear();
+    }
+}
+
+/// A connection's way into a sink that's shared with other connections, see `ingest`.
+pub struct BatchedLog<S> {
+    pub sink: Arc<tokio::sync::Mutex<S>>,
+    /// What's been logged against MAX_FILE_SIZE, shared by everything that writes to the log
+    pub bytes_counter: Arc<tokio::sync::Mutex<usize>>,
+    /// Counts each batch against this limit as it's written, the way ON_FULL=rotate does.
+    /// Without it the caller counts the messages before they're ingested
+    pub size_limit: Option<usize>,
+    pub buffer_bytes: usize, // The batch is written once it holds this much, 0 writes every message
+    pub metrics: Arc<Metrics>,
+    pub batch: EntryBatch,
+}
+
+impl<S: EntrySink> BatchedLog<S> {
+    pub fn new(sink: Arc<tokio::sync::Mutex<S>>, metrics: Arc<Metrics>) -> Self {
+        Self {
+            sink,
+            bytes_counter: Arc::default(),
+            size_limit: None,
+            buffer_bytes: 0,
+            metrics,
+            batch: EntryBatch::default(),
+        }
+    }
+}
+
+/// Logs a message from `client`: its entry is added to the batch, and the batch is persisted
+/// once it holds `buffer_bytes`. `WouldExceed` means the batch didn't fit under the size
+/// limit and is kept whole, so `persist` can write it once there's room.
+pub async fn ingest<S: EntrySink>(
+    log: &mut BatchedLog<S>,
+    client: &str,
+    message: &Message<'_>,
+    dedup: Option<&Mutex<DedupCache>>,
+    options: &EntryOptions,
+    received: Instant,
+) -> io::Result<WriteOutcome> {
+    let logged = log.batch.push(message.logged_len(), received, |out| {
+        encode_message(client, message, dedup, options, out)
+    });
+    if let Some(logged) = logged {
+        log.metrics
+            .dedup_checked(matches!(logged, Dedup::DuplicateOf(_)));
+    }
+    if log.batch.data.len() < log.buffer_bytes {
+        return Ok(WriteOutcome::Written);
+    }
+    persist(log).await
+}
+
+/// Writes the whole batch at once under the lock of the sink, and counts it in the metrics
+/// and against the size of the log.
+pub async fn persist<S: EntrySink>(log: &mut BatchedLog<S>) -> io::Result<WriteOutcome> {
+    let batch = &mut log.batch;
+    if batch.is_empty() {
+        return Ok(WriteOutcome::Written);
+    }
+    let payload_bytes = batch.payload_bytes();
+    let mut sink = log.sink.lock().await;
+    if let Some(limit) = log.size_limit {
+        // Counting under the lock of the sink keeps the count in step with what it holds
+        let size = match sink.is_compressed() {
+            true => 0,
+            false => payload_bytes,
+        };
+        let mut counter = log.bytes_counter.lock().await;
+        // Even a batch that's bigger than the limit goes in once nothing else is counted
+        if *counter > 0 && *counter + size > limit {
+            return Ok(WriteOutcome::WouldExceed);
+        }
+        *counter += size;
+    }
+    let archived = sink.make_room(batch.len()).await?;
+    if archived > 0 {
+        let mut counter = log.bytes_counter.lock().await;
+        *counter = counter.saturating_sub(archived);
+    }
+    match sink
+        .write_entries(&batch.data, &batch.received, payload_bytes)
+        .await
+    {
+        Ok(()) => {}
+        // Only a FIFO without a reader, its entries are dropped until one shows up
+        Err(e) if e.kind() == io::ErrorKind::NotConnected => {
+            for _ in &batch.sizes {
+                log.metrics.message_dropped(DropReason::NoReader);
+            }
+            batch.clear();
+            return Ok(WriteOutcome::Written);
+        }
+        Err(e) => return Err(e),
+    }
+    let written = sink.take_written();
+    drop(sink);
+    for &size in &batch.sizes {
+        log.metrics.message_logged(size);
+    }
+    batch.clear();
+    if written > 0 {
+        *log.bytes_counter.lock().await += written;
+    }
+    Ok(WriteOutcome::Written)
 }
 
 pub fn human_readable_size(size: usize) -> String {
@@ -37,3 +1564,1189 @@ pub fn human_readable_size(size: usize) -> String {
     };
     format!("{size_fmt} {unit}")
 }
+
+/// e.g. `45s`, `12m 5s`, `3h 20m` or `2d 4h`, only the two largest units.
+pub fn human_readable_duration(secs: u64) -> String {
+    let (days, hours, minutes) = (secs / 86_400, secs / 3600 % 24, secs / 60 % 60);
+    match (days, hours, minutes) {
+        (0, 0, 0) => format!("{secs}s"),
+        (0, 0, m) => format!("{m}m {}s", secs % 60),
+        (0, h, m) => format!("{h}h {m}m"),
+        (d, h, _) => format!("{d}d {h}h"),
+    }
+}
+
+/// When a log of `size` bytes reaches `limit` if it keeps growing at `bytes_per_sec`, or
+/// `None` if it isn't growing.
+pub fn time_to_full(size: usize, limit: usize, bytes_per_sec: f64) -> Option<Duration> {
+    if bytes_per_sec <= 0.0 || limit == usize::MAX {
+        return None; // Not growing, or nothing to fill like a FIFO
+    }
+    let room = limit.saturating_sub(size) as f64;
+    Some(Duration::from_secs_f64(room / bytes_per_sec))
+}
+
+pub const DEFAULT_RATE_WINDOW_SECS: u64 = 60;
+
+#[derive(Debug, Clone, Copy, Default)]
+struct RateSlot {
+    second: u64,
+    bytes: u64,
+    messages: u64,
+}
+
+/// A ring buffer of per-second counters, used to compute throughput over the last `window` seconds.
+#[derive(Debug, Clone)]
+pub struct RateWindow {
+    slots: Vec<RateSlot>,
+}
+
+impl RateWindow {
+    pub fn new(window_secs: u64) -> Self {
+        Self {
+            slots: vec![RateSlot::default(); window_secs.max(1) as usize],
+        }
+    }
+
+    pub fn window_secs(&self) -> u64 {
+        self.slots.len() as u64
+    }
+
+    pub fn record(&mut self, second: u64, bytes: u64) {
+        let len = self.slots.len();
+        let slot = &mut self.slots[(second % len as u64) as usize];
+        if slot.second != second {
+            *slot = RateSlot {
+                second,
+                ..Default::default()
+            };
+        }
+        slot.bytes += bytes;
+        slot.messages += 1;
+    }
+
+    /// Returns `(bytes_per_sec, messages_per_sec)` averaged over the window ending at `second`.
+    pub fn rates(&self, second: u64) -> (f64, f64) {
+        let window = self.window_secs();
+        let (bytes, messages) = self
+            .slots
+            .iter()
+            .filter(|slot| slot.second <= second && second - slot.second < window)
+            .fold((0, 0), |(b, m), slot| (b + slot.bytes, m + slot.messages));
+        (
+            bytes as f64 / window as f64,
+            messages as f64 / window as f64,
+        )
+    }
+}
+
+impl Default for RateWindow {
+    fn default() -> Self {
+        Self::new(DEFAULT_RATE_WINDOW_SECS)
+    }
+}
+
+/// Upper bounds (in microseconds) of the flush latency histogram buckets, the last bucket is +Inf.
+pub const LATENCY_BUCKETS_MICROS: [u64; 8] = [
+    1_000, 5_000, 10_000, 50_000, 100_000, 500_000, 1_000_000, 5_000_000,
+];
+const LATENCY_BUCKET_COUNT: usize = LATENCY_BUCKETS_MICROS.len() + 1;
+
+#[derive(Debug, Default)]
+pub struct LatencyHistogram {
+    buckets: [AtomicU64; LATENCY_BUCKET_COUNT],
+    sum_micros: AtomicU64,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LatencySnapshot {
+    pub buckets: [u64; LATENCY_BUCKET_COUNT],
+    pub sum_micros: u64,
+}
+
+impl LatencyHistogram {
+    pub fn record(&self, elapsed: Duration) {
+        let micros = elapsed.as_micros().min(u64::MAX as u128) as u64;
+        let bucket = LATENCY_BUCKETS_MICROS
+            .iter()
+            .position(|&bound| micros <= bound)
+            .unwrap_or(LATENCY_BUCKETS_MICROS.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add(micros, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> LatencySnapshot {
+        LatencySnapshot {
+            buckets: std::array::from_fn(|i| self.buckets[i].load(Ordering::Relaxed)),
+            sum_micros: self.sum_micros.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl LatencySnapshot {
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().sum()
+    }
+
+    /// The upper bound of the bucket containing quantile `q`, `None` when nothing was
+    /// recorded or the quantile falls in the +Inf bucket.
+    pub fn quantile(&self, q: f64) -> Option<Duration> {
+        let count = self.count();
+        if count == 0 {
+            return None;
+        }
+        let rank = ((count as f64) * q).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (i, bucket) in self.buckets.iter().enumerate() {
+            seen += bucket;
+            if seen >= rank {
+                return LATENCY_BUCKETS_MICROS
+                    .get(i)
+                    .map(|&b| Duration::from_micros(b));
+            }
+        }
+        None
+    }
+}
+
+fn format_quantile(latency: Option<Duration>) -> String {
+    match latency {
+        Some(d) => format!("<={}ms", d.as_millis()),
+        None => "-".to_string(),
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    connections_total: AtomicU64,
+    connections_active: AtomicU64,
+    connections_rejected: AtomicU64, // Turned away at MAX_CONNECTIONS
+    messages_total: AtomicU64,       // Messages written to the log
+    bytes_total: AtomicU64,
+    dropped: [AtomicU64; DropReason::ALL.len()],
+    dedup_checked: AtomicU64,
+    dedup_hits: AtomicU64,
+    write_stalls: AtomicU64, // Writes slower than WRITE_STALL_MS
+    time_to_full: Mutex<Option<Duration>>, // Projected by FULL_WARN_SECS
+    file_entries: AtomicU64, // Entries in the current log files
+    max_file_entries: AtomicU64, // MAX_ENTRIES_PER_FILE, 0 is no limit
+    rate_window: Mutex<RateWindow>,
+    upstreams: Mutex<Vec<Arc<UpstreamStats>>>,
+    flush_latency: LatencyHistogram,
+}
+
+type UpstreamCounter = fn(&UpstreamStats) -> &AtomicU64;
+
+#[derive(Debug, Default)]
+pub struct UpstreamStats {
+    pub addr: String,
+    pub forwarded: AtomicU64,
+    pub failed: AtomicU64, // Messages that couldn't be sent, or were dropped while disconnected
+}
+
+/// Why a received message wasn't written to the log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DropReason {
+    Oversize,
+    LogFull,
+    BadChecksum,
+    NoReader,       // Nothing was reading the FIFO log
+    BadCompression, // The rest of a connection after invalid INGEST_COMPRESS data
+}
+
+impl DropReason {
+    pub const ALL: [Self; 5] = [
+        Self::Oversize,
+        Self::LogFull,
+        Self::BadChecksum,
+        Self::NoReader,
+        Self::BadCompression,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Oversize => "oversize",
+            Self::LogFull => "log_full",
+            Self::BadChecksum => "bad_checksum",
+            Self::NoReader => "no_reader",
+            Self::BadCompression => "bad_compression",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct MetricsSnapshot {
+    pub connections_total: u64,
+    pub connections_active: u64,
+    pub connections_rejected: u64,
+    pub messages_total: u64,
+    pub bytes_total: u64,
+    pub dropped: [u64; DropReason::ALL.len()], // Indexed like `DropReason::ALL`
+    pub dedup_checked: u64,
+    pub dedup_hits: u64,
+    pub write_stalls: u64,
+    pub time_to_full: Option<Duration>,
+    pub file_entries: u64,
+    pub max_file_entries: u64,
+    pub rate_window_secs: u64,
+    pub bytes_per_sec: f64,
+    pub messages_per_sec: f64,
+    pub flush_latency: LatencySnapshot,
+    pub fds: FdUsage,
+}
+
+/// Open file descriptors and the `RLIMIT_NOFILE` limits, `None` where the platform
+/// doesn't report them. An unlimited limit is `u64::MAX`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FdUsage {
+    pub open: Option<u64>,
+    pub soft_limit: Option<u64>,
+    pub hard_limit: Option<u64>,
+}
+
+#[cfg(unix)]
+pub fn fd_usage() -> FdUsage {
+    // Counting the entries of /dev/fd includes the descriptor used to read it
+    let open = std::fs::read_dir("/dev/fd")
+        .ok()
+        .map(|entries| entries.count().saturating_sub(1) as u64);
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    // SAFETY: getrlimit only writes to the struct it's given
+    let result = unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) };
+    #[allow(clippy::unnecessary_cast)] // rlim_t isn't u64 on every platform
+    let (soft_limit, hard_limit) = match result {
+        0 => (Some(limit.rlim_cur as u64), Some(limit.rlim_max as u64)),
+        _ => (None, None),
+    };
+    FdUsage {
+        open,
+        soft_limit,
+        hard_limit,
+    }
+}
+
+#[cfg(not(unix))]
+pub fn fd_usage() -> FdUsage {
+    FdUsage::default()
+}
+
+/// The name of this machine, the default `INSTANCE_TAG`.
+#[cfg(unix)]
+pub fn hostname() -> Option<String> {
+    let mut name = [0u8; 256];
+    // SAFETY: gethostname writes at most `name.len()` bytes into the buffer it's given
+    if unsafe { libc::gethostname(name.as_mut_ptr().cast(), name.len()) } != 0 {
+        return None;
+    }
+    let len = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+    String::from_utf8(name[..len].to_vec())
+        .ok()
+        .filter(|name| !name.is_empty())
+}
+
+#[cfg(not(unix))]
+pub fn hostname() -> Option<String> {
+    std::env::var("COMPUTERNAME").ok()
+}
+
+/// The host name that `ip` resolves back to, for `REVERSE_DNS`. Blocks until the resolver
+/// answers, `None` when it has no name for it.
+#[cfg(unix)]
+pub fn reverse_dns(ip: IpAddr) -> Option<String> {
+    let addr = socket2::SockAddr::from(SocketAddr::new(ip, 0));
+    let mut name = [0u8; libc::NI_MAXHOST as usize];
+    // SAFETY: `addr` is a valid socket address of `addr.len()` bytes, and getnameinfo writes
+    // at most `name.len()` bytes into the buffer it's given
+    let result = unsafe {
+        libc::getnameinfo(
+            addr.as_ptr().cast(),
+            addr.len(),
+            name.as_mut_ptr().cast(),
+            name.len() as libc::socklen_t,
+            std::ptr::null_mut(),
+            0,
+            libc::NI_NAMEREQD,
+        )
+    };
+    if result != 0 {
+        return None;
+    }
+    let len = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+    String::from_utf8(name[..len].to_vec())
+        .ok()
+        .filter(|name| !name.is_empty())
+}
+
+#[cfg(not(unix))]
+pub fn reverse_dns(_ip: IpAddr) -> Option<String> {
+    None
+}
+
+/// Binds like `TcpListener::bind`, but with room for `backlog` connections waiting to be
+/// accepted rather than a fixed 1024.
+pub fn bind_listener(addr: SocketAddr, backlog: u32) -> io::Result<tokio::net::TcpListener> {
+    let socket = match addr {
+        SocketAddr::V4(_) => tokio::net::TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => tokio::net::TcpSocket::new_v6()?,
+    };
+    #[cfg(not(windows))]
+    socket.set_reuseaddr(true)?;
+    socket.bind(addr)?;
+    socket.listen(backlog)
+}
+
+fn format_fd_limit(limit: Option<u64>) -> String {
+    match limit {
+        Some(u64::MAX) => "unlimited".to_string(),
+        Some(limit) => limit.to_string(),
+        None => "unknown".to_string(),
+    }
+}
+
+impl Metrics {
+    pub fn new(rate_window_secs: u64) -> Self {
+        Self {
+            rate_window: Mutex::new(RateWindow::new(rate_window_secs)),
+            ..Default::default()
+        }
+    }
+
+    /// Adds the counters of an upstream that messages are forwarded to.
+    pub fn register_upstream(&self, addr: &str) -> Arc<UpstreamStats> {
+        let stats = Arc::new(UpstreamStats {
+            addr: addr.to_string(),
+            ..Default::default()
+        });
+        if let Ok(mut upstreams) = self.upstreams.lock() {
+            upstreams.push(Arc::clone(&stats));
+        }
+        stats
+    }
+
+    pub fn connection_opened(self: &Arc<Self>) -> ConnectionGuard {
+        self.connections_total.fetch_add(1, Ordering::Relaxed);
+        self.connections_active.fetch_add(1, Ordering::Relaxed);
+        ConnectionGuard(Arc::clone(self))
+    }
+
+    /// Like `connection_opened`, unless `max` connections are already open (0 is no limit),
+    /// in which case the connection is counted as rejected instead.
+    pub fn try_connection_opened(self: &Arc<Self>, max: u64) -> Option<ConnectionGuard> {
+        if max == 0 {
+            return Some(self.connection_opened());
+        }
+        let admitted =
+            self.connections_active
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |active| {
+                    (active < max).then_some(active + 1)
+                });
+        match admitted {
+            Ok(_) => {
+                self.connections_total.fetch_add(1, Ordering::Relaxed);
+                Some(ConnectionGuard(Arc::clone(self)))
+            }
+            Err(_) => {
+                self.connections_rejected.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    pub fn flush_completed(&self, received: std::time::Instant) {
+        self.flush_latency.record(received.elapsed());
+    }
+
+    pub fn message_logged(&self, bytes: usize) {
+        self.messages_total.fetch_add(1, Ordering::Relaxed);
+        self.bytes_total.fetch_add(bytes as u64, Ordering::Relaxed);
+        let second = (now() / 1000) as u64;
+        if let Ok(mut window) = self.rate_window.lock() {
+            window.record(second, bytes as u64);
+        }
+    }
+
+    pub fn message_dropped(&self, reason: DropReason) {
+        self.dropped[reason as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn dedup_checked(&self, hit: bool) {
+        self.dedup_checked.fetch_add(1, Ordering::Relaxed);
+        if hit {
+            self.dedup_hits.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn write_stalled(&self) {
+        self.write_stalls.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn limit_file_entries(&self, max: u64) {
+        self.max_file_entries.store(max, Ordering::Relaxed);
+    }
+
+    pub fn file_entries_written(&self, n: u64) {
+        self.file_entries.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// A log file holding `n` entries was archived.
+    pub fn file_entries_archived(&self, n: u64) {
+        self.file_entries.fetch_sub(n, Ordering::Relaxed);
+    }
+
+    pub fn set_time_to_full(&self, projected: Option<Duration>) {
+        if let Ok(mut time_to_full) = self.time_to_full.lock() {
+            *time_to_full = projected;
+        }
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        let second = (now() / 1000) as u64;
+        let (rate_window_secs, (bytes_per_sec, messages_per_sec)) = match self.rate_window.lock() {
+            Ok(window) => (window.window_secs(), window.rates(second)),
+            Err(_) => (0, (0.0, 0.0)),
+        };
+        MetricsSnapshot {
+            connections_total: self.connections_total.load(Ordering::Relaxed),
+            connections_active: self.connections_active.load(Ordering::Relaxed),
+            connections_rejected: self.connections_rejected.load(Ordering::Relaxed),
+            messages_total: self.messages_total.load(Ordering::Relaxed),
+            bytes_total: self.bytes_total.load(Ordering::Relaxed),
+            dropped: self
+                .dropped
+                .each_ref()
+                .map(|count| count.load(Ordering::Relaxed)),
+            dedup_checked: self.dedup_checked.load(Ordering::Relaxed),
+            dedup_hits: self.dedup_hits.load(Ordering::Relaxed),
+            write_stalls: self.write_stalls.load(Ordering::Relaxed),
+            time_to_full: self.time_to_full.lock().ok().and_then(|t| *t),
+            file_entries: self.file_entries.load(Ordering::Relaxed),
+            max_file_entries: self.max_file_entries.load(Ordering::Relaxed),
+            rate_window_secs,
+            bytes_per_sec,
+            messages_per_sec,
+            flush_latency: self.flush_latency.snapshot(),
+            fds: fd_usage(),
+        }
+    }
+}
+
+impl MetricsSnapshot {
+    pub fn dropped_total(&self) -> u64 {
+        self.dropped.iter().sum()
+    }
+
+    /// e.g. `3 dropped (oversize: 2, log_full: 1, bad_checksum: 0, ...)`
+    pub fn dropped_summary(&self) -> String {
+        let reasons: Vec<String> = DropReason::ALL
+            .iter()
+            .zip(self.dropped)
+            .map(|(reason, count)| format!("{}: {count}", reason.as_str()))
+            .collect();
+        format!("{} dropped ({})", self.dropped_total(), reasons.join(", "))
+    }
+
+    /// The share of checked payloads that were duplicates, from 0 to 1.
+    pub fn dedup_hit_rate(&self) -> f64 {
+        match self.dedup_checked {
+            0 => 0.0,
+            checked => self.dedup_hits as f64 / checked as f64,
+        }
+    }
+}
+
+/// Decrements the active connections gauge when dropped, so every exit path of a
+/// connection task (including errors and panics) is accounted for.
+pub struct ConnectionGuard(Arc<Metrics>);
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0.connections_active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+pub fn stats_line(snapshot: &MetricsSnapshot) -> String {
+    let mut line = format!(
+        "Stats | connections: {} active, {} total | messages: {} written ({}), {} | rate: {}/s, {:.2} msg/s ({}s window) | flush latency p50: {}, p99: {}",
+        snapshot.connections_active,
+        snapshot.connections_total,
+        snapshot.messages_total,
+        human_readable_size(snapshot.bytes_total as usize),
+        snapshot.dropped_summary(),
+        human_readable_size(snapshot.bytes_per_sec as usize),
+        snapshot.messages_per_sec,
+        snapshot.rate_window_secs,
+        format_quantile(snapshot.flush_latency.quantile(0.5)),
+        format_quantile(snapshot.flush_latency.quantile(0.99)),
+    );
+    if snapshot.dedup_checked > 0 {
+        let _ = write!(
+            line,
+            " | dedup: {} of {} duplicates ({:.1}%)",
+            snapshot.dedup_hits,
+            snapshot.dedup_checked,
+            snapshot.dedup_hit_rate() * 100.0
+        );
+    }
+    if snapshot.connections_rejected > 0 {
+        let _ = write!(
+            line,
+            " | rejected connections: {}",
+            snapshot.connections_rejected
+        );
+    }
+    if snapshot.write_stalls > 0 {
+        let _ = write!(line, " | write stalls: {}", snapshot.write_stalls);
+    }
+    if snapshot.max_file_entries > 0 {
+        let _ = write!(
+            line,
+            " | entries in current files: {} (max {} per file)",
+            snapshot.file_entries, snapshot.max_file_entries
+        );
+    }
+    if let Some(time_to_full) = snapshot.time_to_full {
+        let _ = write!(
+            line,
+            " | full in: {}",
+            human_readable_duration(time_to_full.as_secs())
+        );
+    }
+    if let Some(open) = snapshot.fds.open {
+        let _ = write!(
+            line,
+            " | fds: {open} open, limit {} (hard {})",
+            format_fd_limit(snapshot.fds.soft_limit),
+            format_fd_limit(snapshot.fds.hard_limit)
+        );
+    }
+    line
+}
+
+fn write_metric(
+    out: &mut String,
+    name: &str,
+    kind: &str,
+    help: &str,
+    value: impl std::fmt::Display,
+) {
+    // Writing into a String can't fail
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+    let _ = writeln!(out, "{name} {value}");
+}
+
+pub fn render_prometheus(metrics: &Metrics) -> String {
+    let upstreams = match metrics.upstreams.lock() {
+        Ok(upstreams) => upstreams.clone(),
+        Err(_) => Vec::new(),
+    };
+    prometheus_text(&metrics.snapshot(), &upstreams)
+}
+
+fn prometheus_text(snapshot: &MetricsSnapshot, upstreams: &[Arc<UpstreamStats>]) -> String {
+    let mut out = String::new();
+    write_metric(
+        &mut out,
+        "scooper_connections_total",
+        "counter",
+        "Total number of accepted connections.",
+        snapshot.connections_total,
+    );
+    write_metric(
+        &mut out,
+        "scooper_connections_active",
+        "gauge",
+        "Number of currently open connections.",
+        snapshot.connections_active,
+    );
+    write_metric(
+        &mut out,
+        "scooper_connections_rejected_total",
+        "counter",
+        "Total number of connections turned away because MAX_CONNECTIONS were open.",
+        snapshot.connections_rejected,
+    );
+    write_metric(
+        &mut out,
+        "scooper_messages_total",
+        "counter",
+        "Total number of logged messages.",
+        snapshot.messages_total,
+    );
+    write_metric(
+        &mut out,
+        "scooper_bytes_total",
+        "counter",
+        "Total number of logged payload bytes.",
+        snapshot.bytes_total,
+    );
+    write_metric(
+        &mut out,
+        "scooper_dedup_checked_total",
+        "counter",
+        "Total number of payloads checked against the recent payloads by DEDUP.",
+        snapshot.dedup_checked,
+    );
+    write_metric(
+        &mut out,
+        "scooper_dedup_hits_total",
+        "counter",
+        "Total number of payloads logged as duplicates of a recent payload.",
+        snapshot.dedup_hits,
+    );
+    write_metric(
+        &mut out,
+        "scooper_write_stalls_total",
+        "counter",
+        "Total number of writes or flushes that took longer than WRITE_STALL_MS.",
+        snapshot.write_stalls,
+    );
+    if snapshot.max_file_entries > 0 {
+        write_metric(
+            &mut out,
+            "scooper_file_entries",
+            "gauge",
+            "Number of entries in the current log files, which rotate at MAX_ENTRIES_PER_FILE.",
+            snapshot.file_entries,
+        );
+    }
+    if let Some(time_to_full) = snapshot.time_to_full {
+        write_metric(
+            &mut out,
+            "scooper_seconds_to_full",
+            "gauge",
+            "Projected seconds until the log reaches MAX_FILE_SIZE at the current rate.",
+            time_to_full.as_secs(),
+        );
+    }
+    let name = "scooper_messages_dropped_total";
+    let _ = writeln!(
+        out,
+        "# HELP {name} Total number of received messages that weren't logged, by reason."
+    );
+    let _ = writeln!(out, "# TYPE {name} counter");
+    for (reason, count) in DropReason::ALL.iter().zip(snapshot.dropped) {
+        let _ = writeln!(out, "{name}{{reason=\"{}\"}} {count}", reason.as_str());
+    }
+    write_metric(
+        &mut out,
+        "scooper_bytes_per_second",
+        "gauge",
+        "Payload bytes per second over the rolling rate window.",
+        snapshot.bytes_per_sec,
+    );
+    write_metric(
+        &mut out,
+        "scooper_messages_per_second",
+        "gauge",
+        "Messages per second over the rolling rate window.",
+        snapshot.messages_per_sec,
+    );
+    let fd_gauges = [
+        (
+            "scooper_open_fds",
+            "Number of open file descriptors.",
+            snapshot.fds.open,
+        ),
+        (
+            "scooper_max_fds",
+            "Soft limit of open file descriptors (RLIMIT_NOFILE).",
+            snapshot.fds.soft_limit,
+        ),
+        (
+            "scooper_max_fds_hard",
+            "Hard limit of open file descriptors (RLIMIT_NOFILE).",
+            snapshot.fds.hard_limit,
+        ),
+    ];
+    for (name, help, value) in fd_gauges {
+        if let Some(value) = value {
+            write_metric(&mut out, name, "gauge", help, value);
+        }
+    }
+    let upstream_counters: [(&str, &str, UpstreamCounter); 2] = [
+        (
+            "scooper_upstream_forwarded_total",
+            "Messages forwarded to each upstream.",
+            |u| &u.forwarded,
+        ),
+        (
+            "scooper_upstream_failed_total",
+            "Messages that couldn't be forwarded to each upstream.",
+            |u| &u.failed,
+        ),
+    ];
+    for (name, help, counter) in upstream_counters {
+        if upstreams.is_empty() {
+            break;
+        }
+        let _ = writeln!(out, "# HELP {name} {help}");
+        let _ = writeln!(out, "# TYPE {name} counter");
+        for upstream in upstreams {
+            let value = counter(upstream).load(Ordering::Relaxed);
+            let _ = writeln!(
+                out,
+                "{name}{{upstream={}}} {value}",
+                json_string(&upstream.addr)
+            );
+        }
+    }
+    let name = "scooper_flush_latency_seconds";
+    let latency = &snapshot.flush_latency;
+    let _ = writeln!(
+        out,
+        "# HELP {name} Time from receiving a message until it was flushed to disk."
+    );
+    let _ = writeln!(out, "# TYPE {name} histogram");
+    let mut cumulative = 0;
+    for (i, bucket) in latency.buckets.iter().enumerate() {
+        cumulative += bucket;
+        let le = match LATENCY_BUCKETS_MICROS.get(i) {
+            Some(&bound) => (bound as f64 / 1_000_000.0).to_string(),
+            None => "+Inf".to_string(),
+        };
+        let _ = writeln!(out, "{name}_bucket{{le=\"{le}\"}} {cumulative}");
+    }
+    let _ = writeln!(
+        out,
+        "{name}_sum {}",
+        latency.sum_micros as f64 / 1_000_000.0
+    );
+    let _ = writeln!(out, "{name}_count {cumulative}");
+    out
+}
+
+/// Counters are sent as the delta since `previous`, gauges as their current value.
+pub fn render_statsd(current: &MetricsSnapshot, previous: &MetricsSnapshot) -> String {
+    let delta = |now: u64, before: u64| now.saturating_sub(before);
+    let dropped = DropReason::ALL.iter().enumerate().map(|(i, reason)| {
+        format!(
+            "scooper.dropped.{}:{}|c",
+            reason.as_str(),
+            delta(current.dropped[i], previous.dropped[i])
+        )
+    });
+    [
+        format!(
+            "scooper.messages:{}|c",
+            delta(current.messages_total, previous.messages_total)
+        ),
+        format!(
+            "scooper.bytes:{}|c",
+            delta(current.bytes_total, previous.bytes_total)
+        ),
+        format!(
+            "scooper.connections:{}|c",
+            delta(current.connections_total, previous.connections_total)
+        ),
+        format!(
+            "scooper.connections_active:{}|g",
+            current.connections_active
+        ),
+        format!(
+            "scooper.connections_rejected:{}|c",
+            delta(current.connections_rejected, previous.connections_rejected)
+        ),
+        format!(
+            "scooper.dedup.checked:{}|c",
+            delta(current.dedup_checked, previous.dedup_checked)
+        ),
+        format!(
+            "scooper.dedup.hits:{}|c",
+            delta(current.dedup_hits, previous.dedup_hits)
+        ),
+        format!(
+            "scooper.write_stalls:{}|c",
+            delta(current.write_stalls, previous.write_stalls)
+        ),
+    ]
+    .into_iter()
+    .chain(
+        current
+            .fds
+            .open
+            .map(|open| format!("scooper.fds.open:{open}|g")),
+    )
+    .chain(
+        current
+            .fds
+            .soft_limit
+            .map(|limit| format!("scooper.fds.max:{limit}|g")),
+    )
+    .chain(
+        (current.max_file_entries > 0)
+            .then(|| format!("scooper.file_entries:{}|g", current.file_entries)),
+    )
+    .chain(
+        current
+            .time_to_full
+            .map(|t| format!("scooper.seconds_to_full:{}|g", t.as_secs())),
+    )
+    .chain(dropped)
+    .collect::<Vec<_>>()
+    .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sanitize_escapes_control_characters() {
+        let payload = b"a\0b\x1b[31mc\x7fd\te\n";
+        let escaped = sanitize_payload(payload, SanitizeMode::Escape, "");
+        assert_eq!(&*escaped, b"a\\x00b\\x1B[31mc\\x7Fd\\x09e\n");
+    }
+
+    #[test]
+    fn sanitize_strips_control_characters() {
+        let payload = b"a\0b\x1b[31mc\x7fd\te\n";
+        let stripped = sanitize_payload(payload, SanitizeMode::Strip, "");
+        assert_eq!(&*stripped, b"ab[31mcde\n");
+        // The SEPARATOR between payloads is kept, only escaping touches it
+        let stripped = sanitize_payload(payload, SanitizeMode::Strip, "\t");
+        assert_eq!(&*stripped, b"ab[31mcd\te\n");
+        let escaped = sanitize_payload(payload, SanitizeMode::Escape, "\t");
+        assert!(escaped.ends_with(b"d\\x09e\n"));
+    }
+
+    #[test]
+    fn sanitize_keeps_multibyte_text_and_c1_controls_escaped() {
+        let payload = "h\u{e9}llo \u{1F600}\u{85}".as_bytes();
+        let escaped = sanitize_payload(payload, SanitizeMode::Escape, "");
+        assert_eq!(&*escaped, "h\u{e9}llo \u{1F600}\\x85".as_bytes());
+    }
+
+    #[test]
+    fn sanitize_leaves_clean_binary_and_unsanitized_payloads_alone() {
+        let clean = b"plain text\n";
+        assert!(matches!(
+            sanitize_payload(clean, SanitizeMode::Escape, ""),
+            Cow::Borrowed(_)
+        ));
+        let binary = b"\xff\0\x1b";
+        assert_eq!(&*sanitize_payload(binary, SanitizeMode::Strip, ""), binary);
+        let raw = b"\0\x1b\x7f";
+        assert_eq!(&*sanitize_payload(raw, SanitizeMode::None, ""), raw);
+    }
+
+    #[test]
+    fn prometheus_text_of_a_known_snapshot() {
+        let snapshot = MetricsSnapshot {
+            connections_total: 12,
+            connections_active: 2,
+            connections_rejected: 1,
+            messages_total: 40,
+            bytes_total: 2048,
+            dropped: [3, 1, 0, 0, 0],
+            dedup_checked: 10,
+            dedup_hits: 4,
+            write_stalls: 0,
+            time_to_full: Some(Duration::from_secs(90)),
+            file_entries: 7,
+            max_file_entries: 100,
+            rate_window_secs: 60,
+            bytes_per_sec: 12.5,
+            messages_per_sec: 0.5,
+            flush_latency: LatencySnapshot {
+                buckets: [3, 1, 0, 0, 0, 0, 0, 0, 1],
+                sum_micros: 7_500_000,
+            },
+            fds: FdUsage {
+                open: Some(9),
+                soft_limit: Some(1024),
+                hard_limit: None,
+            },
+        };
+        let upstream = Arc::new(UpstreamStats {
+            addr: "10.0.0.2:9000".to_string(),
+            forwarded: AtomicU64::new(5),
+            failed: AtomicU64::new(1),
+        });
+        let expected = r#"# HELP scooper_connections_total Total number of accepted connections.
+# TYPE scooper_connections_total counter
+scooper_connections_total 12
+# HELP scooper_connections_active Number of currently open connections.
+# TYPE scooper_connections_active gauge
+scooper_connections_active 2
+# HELP scooper_connections_rejected_total Total number of connections turned away because MAX_CONNECTIONS were open.
+# TYPE scooper_connections_rejected_total counter
+scooper_connections_rejected_total 1
+# HELP scooper_messages_total Total number of logged messages.
+# TYPE scooper_messages_total counter
+scooper_messages_total 40
+# HELP scooper_bytes_total Total number of logged payload bytes.
+# TYPE scooper_bytes_total counter
+scooper_bytes_total 2048
+# HELP scooper_dedup_checked_total Total number of payloads checked against the recent payloads by DEDUP.
+# TYPE scooper_dedup_checked_total counter
+scooper_dedup_checked_total 10
+# HELP scooper_dedup_hits_total Total number of payloads logged as duplicates of a recent payload.
+# TYPE scooper_dedup_hits_total counter
+scooper_dedup_hits_total 4
+# HELP scooper_write_stalls_total Total number of writes or flushes that took longer than WRITE_STALL_MS.
+# TYPE scooper_write_stalls_total counter
+scooper_write_stalls_total 0
+# HELP scooper_file_entries Number of entries in the current log files, which rotate at MAX_ENTRIES_PER_FILE.
+# TYPE scooper_file_entries gauge
+scooper_file_entries 7
+# HELP scooper_seconds_to_full Projected seconds until the log reaches MAX_FILE_SIZE at the current rate.
+# TYPE scooper_seconds_to_full gauge
+scooper_seconds_to_full 90
+# HELP scooper_messages_dropped_total Total number of received messages that weren't logged, by reason.
+# TYPE scooper_messages_dropped_total counter
+scooper_messages_dropped_total{reason="oversize"} 3
+scooper_messages_dropped_total{reason="log_full"} 1
+scooper_messages_dropped_total{reason="bad_checksum"} 0
+scooper_messages_dropped_total{reason="no_reader"} 0
+scooper_messages_dropped_total{reason="bad_compression"} 0
+# HELP scooper_bytes_per_second Payload bytes per second over the rolling rate window.
+# TYPE scooper_bytes_per_second gauge
+scooper_bytes_per_second 12.5
+# HELP scooper_messages_per_second Messages per second over the rolling rate window.
+# TYPE scooper_messages_per_second gauge
+scooper_messages_per_second 0.5
+# HELP scooper_open_fds Number of open file descriptors.
+# TYPE scooper_open_fds gauge
+scooper_open_fds 9
+# HELP scooper_max_fds Soft limit of open file descriptors (RLIMIT_NOFILE).
+# TYPE scooper_max_fds gauge
+scooper_max_fds 1024
+# HELP scooper_upstream_forwarded_total Messages forwarded to each upstream.
+# TYPE scooper_upstream_forwarded_total counter
+scooper_upstream_forwarded_total{upstream="10.0.0.2:9000"} 5
+# HELP scooper_upstream_failed_total Messages that couldn't be forwarded to each upstream.
+# TYPE scooper_upstream_failed_total counter
+scooper_upstream_failed_total{upstream="10.0.0.2:9000"} 1
+# HELP scooper_flush_latency_seconds Time from receiving a message until it was flushed to disk.
+# TYPE scooper_flush_latency_seconds histogram
+scooper_flush_latency_seconds_bucket{le="0.001"} 3
+scooper_flush_latency_seconds_bucket{le="0.005"} 4
+scooper_flush_latency_seconds_bucket{le="0.01"} 4
+scooper_flush_latency_seconds_bucket{le="0.05"} 4
+scooper_flush_latency_seconds_bucket{le="0.1"} 4
+scooper_flush_latency_seconds_bucket{le="0.5"} 4
+scooper_flush_latency_seconds_bucket{le="1"} 4
+scooper_flush_latency_seconds_bucket{le="5"} 4
+scooper_flush_latency_seconds_bucket{le="+Inf"} 5
+scooper_flush_latency_seconds_sum 7.5
+scooper_flush_latency_seconds_count 5
+"#;
+        assert_eq!(prometheus_text(&snapshot, &[upstream]), expected);
+    }
+
+    #[test]
+    fn client_field_truncates_long_host_names() {
+        let addr: SocketAddr = "10.0.0.1:5000".parse().unwrap();
+        let host = format!("{}.example.com", "a".repeat(300));
+        assert_eq!(
+            client_field(Some(&host), &addr, 0),
+            format!("{host}@10.0.0.1:5000")
+        );
+        let field = client_field(Some(&host), &addr, 40);
+        assert_eq!(field.len(), 40);
+        assert_eq!(field, format!("{}...@10.0.0.1:5000", "a".repeat(23)));
+        assert_eq!(
+            client_field(Some("db.lan"), &addr, 40),
+            "db.lan@10.0.0.1:5000"
+        );
+    }
+
+    #[test]
+    fn client_field_keeps_ipv6_addresses_whole() {
+        let addr: SocketAddr = "[2001:db8:85a3::8a2e:370:7334]:443".parse().unwrap();
+        let host = "x".repeat(100);
+        let field = client_field(Some(&host), &addr, 48);
+        assert_eq!(field, "xxxxxxxxxx...@[2001:db8:85a3::8a2e:370:7334]:443");
+        // Too short for any of the name, the address still goes in whole
+        let field = client_field(Some(&host), &addr, 10);
+        assert_eq!(field, "...@[2001:db8:85a3::8a2e:370:7334]:443");
+        assert_eq!(
+            client_field(None, &addr, 10),
+            "[2001:db8:85a3::8a2e:370:7334]:443"
+        );
+    }
+
+    #[test]
+    fn client_field_cuts_names_at_char_boundaries() {
+        let addr: SocketAddr = "[::1]:1".parse().unwrap();
+        let field = client_field(Some("ééééé"), &addr, 14);
+        assert_eq!(field, "é...@[::1]:1");
+        assert_eq!(client_field(Some("a$b\n"), &addr, 0), "a_b_@[::1]:1");
+    }
+
+    #[test]
+    fn dedup_cache_is_bounded_by_bytes() {
+        let mut cache = DedupCache::new(100, 10);
+        assert_eq!(cache.find(b"aaaa"), None);
+        assert_eq!(cache.insert(b"aaaa"), 1);
+        assert_eq!(cache.insert(b"bbbb"), 2);
+        assert_eq!(cache.find(b"aaaa"), Some(1));
+        // 12 bytes are over the budget, the oldest payload goes
+        assert_eq!(cache.insert(b"cccc"), 3);
+        assert_eq!(cache.find(b"aaaa"), None);
+        assert_eq!(cache.find(b"cccc"), Some(3));
+        // Too big to remember at all, but still numbered
+        assert_eq!(cache.insert(b"too big to cache"), 4);
+        assert_eq!(cache.find(b"too big to cache"), None);
+        assert_eq!(cache.find(b"bbbb"), Some(2));
+    }
+
+    #[test]
+    fn dedup_cache_is_bounded_by_count() {
+        let mut cache = DedupCache::new(2, usize::MAX);
+        for payload in [b"a", b"b", b"c"] {
+            cache.insert(payload);
+        }
+        assert_eq!(cache.find(b"a"), None);
+        assert_eq!(cache.find(b"b"), Some(2));
+    }
+
+    #[test]
+    fn duplicates_resolve_to_their_seq_record() {
+        let entry = |payload: &[u8], extra: &str| log_reader::LogEntry {
+            offset: 0,
+            timestamp: 1,
+            client: "c".to_string(),
+            len: payload.len(),
+            extra: vec![extra.to_string()],
+            payload: payload.to_vec(),
+        };
+        let mut duplicates = log_reader::DuplicateResolver::new(1024);
+        let first = entry(b"hello", "seq=1");
+        assert_eq!(duplicates.resolve(&first), Some(&b"hello"[..]));
+        assert_eq!(
+            duplicates.resolve(&entry(b"", "dup=1")),
+            Some(&b"hello"[..])
+        );
+        assert_eq!(duplicates.resolve(&entry(b"", "dup=2")), None);
+        // A restarted server numbers from 1 again
+        let restarted = entry(b"again", "seq=1");
+        assert_eq!(duplicates.resolve(&restarted), Some(&b"again"[..]));
+        assert_eq!(
+            duplicates.resolve(&entry(b"", "dup=1")),
+            Some(&b"again"[..])
+        );
+    }
+
+    /// The frames of `data` that get logged under `policy`, and whether the connection closes.
+    fn empty_message_frames(
+        policy: EmptyMessage,
+        framing: Framing,
+        data: &[u8],
+    ) -> (Vec<Vec<u8>>, bool) {
+        let mut pending = data.to_vec();
+        let mut frames = take_frames(framing, &mut pending, true).unwrap();
+        let close = policy.apply(&mut frames);
+        (frames, close)
+    }
+
+    #[test]
+    fn empty_messages_are_ignored() {
+        let (frames, close) =
+            empty_message_frames(EmptyMessage::Ignore, Framing::Lines, b"a\n\nb\n");
+        assert_eq!(frames, [b"a".to_vec(), b"b".to_vec()]);
+        assert!(!close);
+    }
+
+    #[test]
+    fn empty_messages_are_logged() {
+        let data = [&[0, 0, 0, 1][..], b"a", &[0, 0, 0, 0], &[0, 0, 0, 1], b"b"].concat();
+        let (frames, close) = empty_message_frames(EmptyMessage::Log, Framing::Length, &data);
+        assert_eq!(frames, [b"a".to_vec(), Vec::new(), b"b".to_vec()]);
+        assert!(!close);
+    }
+
+    #[test]
+    fn empty_messages_close_the_connection() {
+        let (frames, close) =
+            empty_message_frames(EmptyMessage::Close, Framing::Lines, b"a\r\n\r\nb\n");
+        assert_eq!(frames, [b"a".to_vec()]);
+        assert!(close);
+        let (frames, close) = empty_message_frames(EmptyMessage::Close, Framing::Lines, b"a\nb");
+        assert_eq!(frames, [b"a".to_vec(), b"b".to_vec()]);
+        assert!(!close);
+    }
+
+    /// A file of `payloads` as the writer lays them out, the first record without a leader.
+    fn records(payloads: &[&[u8]], separator: RecordSeparator) -> Vec<u8> {
+        let mut file = Vec::new();
+        for payload in payloads {
+            let stamp = format_stamp(1, "c", payload.len(), &[], separator);
+            file.extend_from_slice(stamp.as_bytes());
+            file.extend_from_slice(payload);
+            file.extend_from_slice(separator.trailer());
+        }
+        file.strip_prefix(separator.leader()).unwrap().to_vec()
+    }
+
+    #[test]
+    fn format_stamp_places_the_separator() {
+        let leading = format_stamp(1, "c", 2, &[], RecordSeparator::Leading);
+        assert_eq!(leading, "\n$$$1$$$c$$$2$$$\n");
+        let trailing = format_stamp(1, "c", 2, &["text"], RecordSeparator::Trailing);
+        assert_eq!(trailing, "$$$1$$$c$$$2$$$text$$$\n");
+    }
+
+    #[test]
+    fn payload_newlines_survive_both_separators() {
+        let payloads: [&[u8]; 4] = [b"no newline", b"newline\n", b"\n", b"two\n\n"];
+        for separator in [RecordSeparator::Leading, RecordSeparator::Trailing] {
+            let file = records(&payloads, separator);
+            let mut rest = &file[..];
+            for payload in payloads {
+                let log_reader::Parsed::Entry(entry, consumed) =
+                    log_reader::parse_log_entry(rest, true)
+                else {
+                    panic!("{separator:?}: no entry for {payload:?} in {rest:?}");
+                };
+                assert_eq!(entry.payload, payload, "{separator:?}");
+                rest = &rest[consumed..];
+            }
+            assert!(rest.is_empty(), "{separator:?}: {rest:?} is left");
+        }
+    }
+
+    #[tokio::test]
+    async fn ingest_batches_until_buffer_bytes() {
+        let sink = Arc::new(tokio::sync::Mutex::new(Vec::new()));
+        let mut log = BatchedLog {
+            buffer_bytes: 1024,
+            size_limit: Some(10),
+            ..BatchedLog::new(Arc::clone(&sink), Arc::default())
+        };
+        let options = EntryOptions::default();
+        for payload in [b"hello", b"world"] {
+            let message = Message::new(payload, None, &options);
+            let outcome = ingest(&mut log, "c", &message, None, &options, Instant::now());
+            assert_eq!(outcome.await.unwrap(), WriteOutcome::Written);
+        }
+        assert!(sink.lock().await.is_empty());
+        assert_eq!(persist(&mut log).await.unwrap(), WriteOutcome::Written);
+        assert_eq!(log.metrics.snapshot().messages_total, 2);
+        assert_eq!(*log.bytes_counter.lock().await, 10);
+        let file = sink.lock().await.clone();
+        let file = file
+            .strip_prefix(RecordSeparator::Leading.leader())
+            .unwrap();
+        let log_reader::Parsed::Entry(entry, consumed) = log_reader::parse_log_entry(file, true)
+        else {
+            panic!("no entry in {file:?}");
+        };
+        assert_eq!(entry.payload, b"hello");
+        assert!(file[consumed..].ends_with(b"world"));
+        // A full log keeps the batch until there's room for it
+        let message = Message::new(b"again", None, &options);
+        ingest(&mut log, "c", &message, None, &options, Instant::now())
+            .await
+            .unwrap();
+        assert_eq!(persist(&mut log).await.unwrap(), WriteOutcome::WouldExceed);
+        assert_eq!(log.batch.len(), 1);
+        *log.bytes_counter.lock().await = 0;
+        assert_eq!(persist(&mut log).await.unwrap(), WriteOutcome::Written);
+        assert!(log.batch.is_empty());
+    }
+}
@@ -0,0 +1,772 @@
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::collections::{HashMap, VecDeque};
+use std::ffi::OsStr;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Read};
+use std::path::{Path, PathBuf};
+
+use crate::{compressed_path, CompressAlgo, RUN_START, RUN_STOP, SERVER_CLIENT};
+
+const STAMP_START: &[u8] = b"\n$$$";
+const STAMP_END: &[u8] = b"$$$\n";
+const FIELD_DELIMITER: &str = "$$$";
+const RECORD_SEPARATOR: u8 = b'\n';
+const MAX_STAMP_LEN: usize = 64 * 1024;
+const MAX_ENTRY_LEN: usize = 256 * 1024 * 1024;
+const READ_CHUNK_SIZE: usize = 64 * 1024;
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogEntry {
+    pub offset: u64,
+    pub timestamp: u128,
+    pub client: String,
+    pub len: usize,
+    pub extra: Vec<String>, // Any optional stamp fields after `len`
+    pub payload: Vec<u8>,
+}
+
+impl LogEntry {
+    /// Whether the payload is text, using the content tag when the entry has one.
+    pub fn is_text(&self) -> bool {
+        match self.extra.iter().find(|f| *f == "text" || *f == "bin") {
+            Some(tag) => tag == "text",
+            None => std::str::from_utf8(&self.payload).is_ok(),
+        }
+    }
+
+    /// The sequence number of the record holding the payload, if this record was logged as
+    /// a duplicate by `DEDUP=global`.
+    pub fn duplicate_of(&self) -> Option<u64> {
+        self.extra
+            .iter()
+            .find_map(|field| field.strip_prefix("dup=")?.parse().ok())
+    }
+
+    /// The sequence number that the `dup=` records of `DEDUP=global` point back at.
+    pub fn seq(&self) -> Option<u64> {
+        self.extra
+            .iter()
+            .find_map(|field| field.strip_prefix("seq=")?.parse().ok())
+    }
+
+    /// The `INSTANCE_TAG` of the server that logged this record, if it had one.
+    pub fn instance(&self) -> Option<&str> {
+        self.extra
+            .iter()
+            .find_map(|field| field.strip_prefix("instance="))
+    }
+
+    /// `START` or `STOP` if this is a run marker rather than a client message.
+    pub fn run_marker(&self) -> Option<&str> {
+        if self.client != SERVER_CLIENT || self.len != 0 {
+            return None;
+        }
+        self.extra
+            .first()
+            .map(String::as_str)
+            .filter(|kind| *kind == RUN_START || *kind == RUN_STOP)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Parsed {
+    /// A full entry and the number of bytes it took up
+    Entry(LogEntry, usize),
+    /// The data ends before the entry does, more bytes are needed
+    Incomplete,
+    Corrupt(String),
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+/// Parses a single entry from the start of `data`, which is expected to be at a record boundary.
+/// Records are separated by a newline either before the stamp (`\n$$$...$$$\n<payload>`)
+/// or after the payload (`$$$...$$$\n<payload>\n`), see `RECORD_SEPARATOR`. The first record
+/// of a file has no leading separator, so with `at_end` (nothing follows `data`) a record
+/// without either is complete, like the last one of a file with leading separators.
+pub fn parse_log_entry(data: &[u8], at_end: bool) -> Parsed {
+    let leading = data.first() == Some(&RECORD_SEPARATOR);
+    let stamp_start = usize::from(leading);
+    let fields_start = stamp_start + FIELD_DELIMITER.len();
+    let rest = &data[stamp_start..];
+    let prefix_len = rest.len().min(FIELD_DELIMITER.len());
+    if rest[..prefix_len] != FIELD_DELIMITER.as_bytes()[..prefix_len] {
+        return Parsed::Corrupt("expected a stamp".to_string());
+    }
+    if rest.len() < FIELD_DELIMITER.len() {
+        return Parsed::Incomplete;
+    }
+    let search_end = data.len().min(MAX_STAMP_LEN);
+    let stamp_end = match find(&data[stamp_start..search_end], STAMP_END) {
+        Some(i) => stamp_start + i + FIELD_DELIMITER.len(),
+        None if data.len() >= MAX_STAMP_LEN => {
+            return Parsed::Corrupt("stamp is too long".to_string())
+        }
+        None if data[stamp_start..].contains(&b'\n') => {
+            return Parsed::Corrupt("unterminated stamp".to_string())
+        }
+        None => return Parsed::Incomplete,
+    };
+    let stamp = match std::str::from_utf8(&data[fields_start..stamp_end]) {
+        Ok(stamp) => stamp,
+        Err(_) => return Parsed::Corrupt("stamp is not valid UTF-8".to_string()),
+    };
+    let stamp = stamp.strip_suffix(FIELD_DELIMITER).unwrap_or(stamp);
+    let mut fields = stamp.split(FIELD_DELIMITER);
+    let (Some(timestamp), Some(client), Some(len)) = (fields.next(), fields.next(), fields.next())
+    else {
+        return Parsed::Corrupt(format!("stamp has too few fields: {stamp:?}"));
+    };
+    let Ok(timestamp) = timestamp.parse() else {
+        return Parsed::Corrupt(format!("invalid timestamp: {timestamp:?}"));
+    };
+    let len = match len.parse::<usize>() {
+        Ok(len) if len <= MAX_ENTRY_LEN => len,
+        _ => return Parsed::Corrupt(format!("invalid length: {len:?}")),
+    };
+    let payload_start = stamp_end + 1; // The newline ending the stamp
+    let payload_end = payload_start + len;
+    if data.len() < payload_end + usize::from(!leading && !at_end) {
+        return Parsed::Incomplete;
+    }
+    let consumed = match leading {
+        true => payload_end,
+        false if data.len() == payload_end => payload_end,
+        false if data[payload_end] == RECORD_SEPARATOR => payload_end + 1,
+        false => return Parsed::Corrupt("missing record separator".to_string()),
+    };
+    let entry = LogEntry {
+        offset: 0,
+        timestamp,
+        client: client.to_string(),
+        len,
+        extra: fields.map(str::to_string).collect(),
+        payload: data[payload_start..payload_end].to_vec(),
+    };
+    Parsed::Entry(entry, consumed)
+}
+
+#[derive(Debug)]
+pub enum ReadError {
+    Corrupt {
+        offset: u64,
+        reason: String,
+    },
+    /// The last record was cut short, likely because it's still being written
+    Incomplete {
+        offset: u64,
+    },
+    Io(io::Error),
+}
+
+impl fmt::Display for ReadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Corrupt { offset, reason } => {
+                write!(f, "corrupt record at offset {offset}: {reason}")
+            }
+            Self::Incomplete { offset } => write!(f, "incomplete record at offset {offset}"),
+            Self::Io(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for ReadError {}
+
+impl From<io::Error> for ReadError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+/// Streams entries out of a log, buffering only as much as the current entry needs.
+pub struct LogReader<R> {
+    inner: R,
+    buffer: Vec<u8>,
+    pos: usize,
+    offset: u64, // File offset of `buffer[pos]`
+    eof: bool,
+    failed: bool,
+}
+
+impl<R: Read> LogReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self::with_offset(inner, 0)
+    }
+
+    /// A reader whose first byte is at `offset` in the underlying file, for reporting.
+    pub fn with_offset(inner: R, offset: u64) -> Self {
+        Self {
+            inner,
+            buffer: Vec::new(),
+            pos: 0,
+            offset,
+            eof: false,
+            failed: false,
+        }
+    }
+
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    fn fill(&mut self) -> io::Result<bool> {
+        if self.eof {
+            return Ok(false);
+        }
+        if self.pos > 0 {
+            self.buffer.drain(..self.pos);
+            self.pos = 0;
+        }
+        let start = self.buffer.len();
+        self.buffer.resize(start + READ_CHUNK_SIZE, 0);
+        let n = loop {
+            match self.inner.read(&mut self.buffer[start..]) {
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                result => break result,
+            }
+        };
+        self.buffer.truncate(start + *n.as_ref().unwrap_or(&0));
+        let n = n?;
+        self.eof = n == 0;
+        Ok(n > 0)
+    }
+
+    fn advance(&mut self, n: usize) {
+        self.pos += n;
+        self.offset += n as u64;
+    }
+
+    pub fn read_entry(&mut self) -> Result<Option<LogEntry>, ReadError> {
+        loop {
+            if self.pos == self.buffer.len() && !self.fill()? {
+                return Ok(None);
+            }
+            match parse_log_entry(&self.buffer[self.pos..], self.eof) {
+                Parsed::Entry(mut entry, consumed) => {
+                    entry.offset = self.offset;
+                    self.advance(consumed);
+                    return Ok(Some(entry));
+                }
+                Parsed::Corrupt(reason) => {
+                    return Err(ReadError::Corrupt {
+                        offset: self.offset,
+                        reason,
+                    })
+                }
+                Parsed::Incomplete if self.eof => {
+                    return Err(ReadError::Incomplete {
+                        offset: self.offset,
+                    })
+                }
+                Parsed::Incomplete => {
+                    self.fill()?;
+                }
+            }
+        }
+    }
+
+    /// Skips ahead to the next thing that looks like a stamp, after a corrupt record.
+    /// Returns `false` if the end of the data was reached first.
+    pub fn resync(&mut self) -> io::Result<bool> {
+        self.failed = false;
+        self.advance(1.min(self.buffer.len() - self.pos));
+        loop {
+            if let Some(i) = find(&self.buffer[self.pos..], STAMP_START) {
+                self.advance(i);
+                return Ok(true);
+            }
+            // Keep a possible partial match at the end of the buffer
+            let keep = (STAMP_START.len() - 1).min(self.buffer.len() - self.pos);
+            self.advance(self.buffer.len() - self.pos - keep);
+            if !self.fill()? {
+                self.advance(self.buffer.len() - self.pos);
+                return Ok(false);
+            }
+        }
+    }
+}
+
+/// Yields entries until the end of the log or the first error.
+impl<R: Read> Iterator for LogReader<R> {
+    type Item = Result<LogEntry, ReadError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        let result = self.read_entry().transpose();
+        self.failed = matches!(result, Some(Err(_)));
+        result
+    }
+}
+
+/// Merges several logs (e.g. the shards of a sharded log) into one stream ordered by timestamp.
+/// Each log is expected to be ordered by itself. Errors are yielded as soon as they're hit,
+/// except for an incomplete last record, which only ends its own log and is yielded once
+/// the others are done.
+pub struct MergedReader<R> {
+    readers: Vec<LogReader<R>>,
+    heads: Vec<Option<LogEntry>>,
+    incomplete: Vec<ReadError>,
+}
+
+impl<R: Read> MergedReader<R> {
+    pub fn new(readers: Vec<LogReader<R>>) -> Self {
+        let heads = readers.iter().map(|_| None).collect();
+        Self {
+            readers,
+            heads,
+            incomplete: Vec::new(),
+        }
+    }
+}
+
+impl<R: Read> Iterator for MergedReader<R> {
+    type Item = Result<LogEntry, ReadError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        for (reader, head) in self.readers.iter_mut().zip(self.heads.iter_mut()) {
+            if head.is_none() {
+                match reader.next() {
+                    Some(Ok(entry)) => *head = Some(entry),
+                    Some(Err(e @ ReadError::Incomplete { .. })) => self.incomplete.push(e),
+                    Some(Err(e)) => return Some(Err(e)),
+                    None => {}
+                }
+            }
+        }
+        let next = self
+            .heads
+            .iter()
+            .enumerate()
+            .filter_map(|(i, head)| head.as_ref().map(|entry| (i, entry.timestamp)))
+            .min_by_key(|&(_, timestamp)| timestamp);
+        match next {
+            Some((next, _)) => self.heads[next].take().map(Ok),
+            None => self.incomplete.pop().map(Err),
+        }
+    }
+}
+
+/// Resolves the `dup=` records of `DEDUP=global` to the payload of the `seq=` record they
+/// point at, remembering up to `max_bytes` of the most recent payloads. A server with a
+/// bigger DEDUP_WINDOW_BYTES can point back further than that.
+pub struct DuplicateResolver {
+    max_bytes: usize,
+    bytes: usize,
+    payloads: HashMap<u64, Vec<u8>>,
+    order: VecDeque<u64>, // Sequence numbers from oldest to newest
+}
+
+impl DuplicateResolver {
+    pub fn new(max_bytes: usize) -> Self {
+        Self {
+            max_bytes,
+            bytes: 0,
+            payloads: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    /// The payload that `entry` was logged for, `None` for a duplicate whose `seq=` record
+    /// isn't known. Entries have to be resolved in the order they were logged.
+    pub fn resolve<'a>(&'a mut self, entry: &'a LogEntry) -> Option<&'a [u8]> {
+        if let Some(seq) = entry.duplicate_of() {
+            return self.payloads.get(&seq).map(Vec::as_slice);
+        }
+        if let Some(seq) = entry
+            .seq()
+            .filter(|_| entry.payload.len() <= self.max_bytes)
+        {
+            if self.payloads.contains_key(&seq) {
+                // Numbering starts over with each server run, the old payloads are stale
+                self.payloads.clear();
+                self.order.clear();
+                self.bytes = 0;
+            }
+            self.payloads.insert(seq, entry.payload.clone());
+            self.order.push_back(seq);
+            self.bytes += entry.payload.len();
+            while self.bytes > self.max_bytes {
+                let Some(oldest) = self.order.pop_front() else {
+                    break;
+                };
+                if let Some(evicted) = self.payloads.remove(&oldest) {
+                    self.bytes -= evicted.len();
+                }
+            }
+        }
+        Some(&entry.payload)
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct VerifyReport {
+    pub good: usize,
+    pub bad: usize,
+    pub first_bad: Option<(u64, String)>,
+    pub incomplete_tail: Option<u64>,
+    pub runs: usize,
+    /// Runs that started without the previous one stopping
+    pub unclean_stops: usize,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.bad == 0
+    }
+}
+
+pub fn verify_log<R: Read>(reader: R) -> io::Result<VerifyReport> {
+    let mut reader = LogReader::new(reader);
+    let mut report = VerifyReport::default();
+    let mut running = false;
+    loop {
+        match reader.read_entry() {
+            Ok(Some(entry)) => {
+                report.good += 1;
+                match entry.run_marker() {
+                    Some(RUN_START) => {
+                        report.runs += 1;
+                        report.unclean_stops += usize::from(running);
+                        running = true;
+                    }
+                    Some(_) => running = false,
+                    None => {}
+                }
+            }
+            Ok(None) => break,
+            Err(ReadError::Incomplete { offset }) => {
+                report.incomplete_tail = Some(offset);
+                break;
+            }
+            Err(ReadError::Corrupt { offset, reason }) => {
+                report.bad += 1;
+                report.first_bad.get_or_insert((offset, reason));
+                if !reader.resync()? {
+                    break;
+                }
+            }
+            Err(ReadError::Io(e)) => return Err(e),
+        }
+    }
+    Ok(report)
+}
+
+/// How a log file is compressed, by its extension or else its first bytes.
+pub fn compression_of(path: &Path) -> io::Result<CompressAlgo> {
+    for algo in [CompressAlgo::Gzip, CompressAlgo::Zstd] {
+        if path
+            .extension()
+            .is_some_and(|ext| Some(ext) == algo.extension().map(OsStr::new))
+        {
+            return Ok(algo);
+        }
+    }
+    let mut reader = BufReader::new(File::open(path)?);
+    let start = reader.fill_buf()?;
+    Ok(if start.starts_with(GZIP_MAGIC) {
+        CompressAlgo::Gzip
+    } else if start.starts_with(ZSTD_MAGIC) {
+        CompressAlgo::Zstd
+    } else {
+        CompressAlgo::None
+    })
+}
+
+/// The decompressed data of a log file, which ends with an `UnexpectedEof` error where a
+/// compressed stream was cut short.
+fn decompressed(path: &Path) -> io::Result<Box<dyn Read>> {
+    let reader = BufReader::new(File::open(path)?);
+    Ok(match compression_of(path)? {
+        CompressAlgo::Gzip => Box::new(MultiGzDecoder::new(reader)),
+        CompressAlgo::Zstd => Box::new(zstd::Decoder::with_buffer(reader)?),
+        CompressAlgo::None => Box::new(reader),
+    })
+}
+
+/// Ends a compressed stream quietly where its data does, because the stream of a compressed
+/// log that's still being written (or wasn't closed cleanly) has no end yet.
+struct Unfinished<R>(R);
+
+impl<R: Read> Read for Unfinished<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.0.read(buf) {
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(0),
+            result => result,
+        }
+    }
+}
+
+/// Whether a compressed file ends where its stream does, rather than being cut short by a
+/// crash. Always true for an uncompressed file.
+pub fn is_finished_compressed(path: &Path) -> io::Result<bool> {
+    if compression_of(path)? == CompressAlgo::None {
+        return Ok(true);
+    }
+    match io::copy(&mut decompressed(path)?, &mut io::sink()) {
+        Ok(_) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Opens a log file for reading, transparently decompressing gzip and zstd files.
+pub fn open_log(path: &Path) -> io::Result<Box<dyn Read>> {
+    Ok(Box::new(Unfinished(decompressed(path)?)))
+}
+
+/// Counts the bytes that pass through the inner reader.
+pub struct CountingReader<R> {
+    inner: R,
+    count: u64,
+}
+
+impl<R> CountingReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self { inner, count: 0 }
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct LogSummary {
+    pub records: usize,
+    pub first_timestamp: Option<u128>,
+    pub last_timestamp: Option<u128>,
+    pub bytes: u64, // Uncompressed size
+    pub error: Option<String>,
+}
+
+/// Counts the records in a log and the range of their timestamps, stopping at the first error.
+pub fn summarize_log<R: Read>(reader: R) -> io::Result<LogSummary> {
+    let mut reader = LogReader::new(CountingReader::new(reader));
+    let mut summary = LogSummary::default();
+    for entry in reader.by_ref() {
+        match entry {
+            Ok(entry) => {
+                summary.records += 1;
+                summary.first_timestamp.get_or_insert(entry.timestamp);
+                summary.last_timestamp = Some(entry.timestamp);
+            }
+            Err(ReadError::Io(e)) => return Err(e),
+            Err(e) => summary.error = Some(e.to_string()),
+        }
+    }
+    let mut inner = reader.into_inner();
+    io::copy(&mut inner, &mut io::sink())?; // Count whatever is left after an error
+    summary.bytes = inner.count();
+    Ok(summary)
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CompactReport {
+    pub kept: usize,
+    pub dropped: usize,
+    pub reclaimed_bytes: u64,
+}
+
+/// Rewrites an uncompressed log without the records older than `cutoff`, copying the
+/// surviving records byte for byte. The new log is written next to the old one and renamed
+/// over it, so a crash leaves either the old or the new log. The log is left untouched if
+/// nothing is dropped or it doesn't parse to the end.
+pub fn compact_log(path: &Path, cutoff: u128) -> io::Result<CompactReport> {
+    let mut reader = LogReader::new(BufReader::new(File::open(path)?));
+    let mut source = BufReader::new(File::open(path)?);
+    let mut temp_name = path.as_os_str().to_owned();
+    temp_name.push(".compact");
+    let temp_path = PathBuf::from(temp_name);
+    let mut output = BufWriter::new(File::create(&temp_path)?);
+    let mut report = CompactReport::default();
+    let result = loop {
+        let start = reader.offset();
+        let entry = match reader.read_entry() {
+            Ok(Some(entry)) => entry,
+            Ok(None) => break Ok(()),
+            Err(ReadError::Io(e)) => break Err(e),
+            Err(e) => break Err(io::Error::new(io::ErrorKind::InvalidData, e.to_string())),
+        };
+        let len = reader.offset() - start;
+        let copied = if entry.timestamp < cutoff {
+            report.dropped += 1;
+            report.reclaimed_bytes += len;
+            source.seek_relative(len as i64)
+        } else {
+            report.kept += 1;
+            io::copy(&mut source.by_ref().take(len), &mut output).map(drop)
+        };
+        if let Err(e) = copied {
+            break Err(e);
+        }
+    };
+    let replaced = result.and_then(|()| {
+        if report.dropped == 0 {
+            return Ok(false); // Nothing to reclaim, the log stays as it was
+        }
+        output
+            .into_inner()
+            .map_err(|e| e.into_error())?
+            .sync_all()?;
+        fs::rename(&temp_path, path)?;
+        Ok(true)
+    });
+    match replaced {
+        Ok(true) => Ok(report),
+        Ok(false) => fs::remove_file(&temp_path).map(|()| report),
+        Err(e) => {
+            let _ = fs::remove_file(&temp_path);
+            Err(e)
+        }
+    }
+}
+
+/// Compresses a rotated archive with `algo` at `level`, replacing it with the compressed
+/// file (see `compressed_path`) that is returned. That's written next to the archive and
+/// renamed, so a crash leaves the uncompressed archive in place.
+pub fn compress_archive(path: &Path, algo: CompressAlgo, level: u32) -> io::Result<PathBuf> {
+    let compressed = compressed_path(path, algo);
+    if compressed == path {
+        return Ok(compressed); // COMPRESS_ALGO=none or already compressed
+    }
+    let mut temp_name = compressed.as_os_str().to_owned();
+    temp_name.push(".partial");
+    let temp_path = PathBuf::from(temp_name);
+    let written = File::open(path).and_then(|mut source| {
+        let output = BufWriter::new(File::create(&temp_path)?);
+        let output = match algo {
+            CompressAlgo::Gzip => {
+                let mut encoder = GzEncoder::new(output, Compression::new(level));
+                io::copy(&mut source, &mut encoder)?;
+                encoder.finish()?
+            }
+            CompressAlgo::Zstd => {
+                let mut encoder = zstd::Encoder::new(output, level as i32)?;
+                io::copy(&mut source, &mut encoder)?;
+                encoder.finish()?
+            }
+            CompressAlgo::None => output,
+        };
+        output
+            .into_inner()
+            .map_err(|e| e.into_error())?
+            .sync_all()?;
+        fs::rename(&temp_path, &compressed)
+    });
+    if let Err(e) = written {
+        let _ = fs::remove_file(&temp_path);
+        return Err(e);
+    }
+    fs::remove_file(path)?;
+    Ok(compressed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LEADING: &[u8] = b"\n$$$1700000000000$$$10.0.0.1:5000$$$5$$$text$$$\nhello";
+    const TRAILING: &[u8] = b"$$$1700000000000$$$10.0.0.1:5000$$$5$$$text$$$\nhello\n";
+
+    /// The offsets in `record` right after each field and delimiter of its stamp, and in and
+    /// after its payload.
+    fn field_boundaries(record: &[u8]) -> Vec<usize> {
+        let mut boundaries: Vec<usize> = (0..record.len())
+            .filter(|&i| record[..i].ends_with(b"$") || record[..i].ends_with(b"\n"))
+            .collect();
+        let payload = record.len() - b"hello".len() - usize::from(!record.starts_with(b"\n"));
+        boundaries.extend([payload + 1, payload + 4]);
+        boundaries.sort();
+        boundaries.dedup();
+        boundaries
+    }
+
+    fn test_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("scooper-{name}-{}.log", std::process::id()))
+    }
+
+    #[test]
+    fn truncated_records_are_incomplete() {
+        for record in [LEADING, TRAILING] {
+            let boundaries = field_boundaries(record);
+            assert!(boundaries.len() > 10, "{boundaries:?}");
+            for cut in boundaries {
+                assert_eq!(
+                    parse_log_entry(&record[..cut], false),
+                    Parsed::Incomplete,
+                    "cut at {cut} of {:?}",
+                    String::from_utf8_lossy(record)
+                );
+            }
+            assert!(matches!(
+                parse_log_entry(record, false),
+                Parsed::Entry(_, consumed) if consumed == record.len()
+            ));
+        }
+    }
+
+    #[test]
+    fn reader_reports_a_truncated_last_record() {
+        for record in [LEADING, TRAILING] {
+            let first = record.strip_prefix(b"\n").unwrap_or(record);
+            // The first record takes a leading newline after it for its trailer
+            let leader = usize::from(record.starts_with(b"\n"));
+            // At the end of a file a record without its trailer is complete, see `parse_log_entry`
+            let payload_end = record.len() - usize::from(record.ends_with(b"\n"));
+            for cut in field_boundaries(record)
+                .into_iter()
+                .filter(|&cut| cut < payload_end)
+            {
+                let file = [first, &record[..cut]].concat();
+                let mut reader = LogReader::new(&file[..]);
+                let entry = reader.next().unwrap().unwrap();
+                assert_eq!(entry.payload, b"hello");
+                match reader.next() {
+                    None if cut <= leader => {}
+                    Some(Err(ReadError::Incomplete { offset })) => {
+                        assert_eq!(offset, (first.len() + leader) as u64, "cut at {cut}");
+                    }
+                    other => panic!("cut at {cut}: {other:?}"),
+                }
+                assert!(reader.next().is_none());
+            }
+        }
+    }
+
+    #[test]
+    fn compact_log_leaves_no_temp_file() {
+        let path = test_path("compact");
+        let temp = path.with_extension("log.compact");
+        fs::write(&path, b"$$$1$$$c$$$3$$$\nold\n$$$5$$$c$$$3$$$\nnew").unwrap();
+        let report = compact_log(&path, 2).unwrap();
+        assert_eq!((report.kept, report.dropped), (1, 1));
+        assert_eq!(fs::read(&path).unwrap(), b"$$$5$$$c$$$3$$$\nnew");
+        assert!(!temp.exists());
+        fs::write(&path, b"$$$1$$$c$$$3$$$\nold\ngarbage").unwrap();
+        assert!(compact_log(&path, 2).is_err());
+        assert!(!temp.exists());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,135 +1,2461 @@
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
 use std::process::exit;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Once, PoisonError, RwLock};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
 use std::{env, io};
-use tokio::fs::{File, OpenOptions};
-use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader, BufWriter};
-use tokio::net::{TcpListener, TcpStream};
+
+use async_compression::tokio::bufread::GzipDecoder;
+use async_compression::tokio::write::{GzipEncoder, ZstdEncoder};
+use async_compression::Level;
+use bytes::Bytes;
+use socket2::SockRef;
+use tokio::fs::{self, File, OpenOptions};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, BufWriter};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::runtime::Builder;
 use tokio::signal::ctrl_c;
-use tokio::sync::Mutex;
+use tokio::sync::{mpsc, watch, Mutex};
+use tokio::task::{JoinHandle, JoinSet};
+use tokio::time::{interval, sleep, timeout, timeout_at};
+
+mod commands;
+#[cfg(unix)]
+mod fifo;
+mod upstream;
+
+const STATSD_ERROR_LOG_INTERVAL: Duration = Duration::from_secs(60);
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(50);
+// How long a new connection gets before MIN_BYTES_PER_SEC applies
+const SLOW_CLIENT_GRACE: Duration = Duration::from_secs(5);
+const PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+const REVERSE_DNS_TIMEOUT: Duration = Duration::from_secs(2); // Logged without a name after
+const BUSY_WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+const FULL_WARN_CHECK_INTERVAL: Duration = Duration::from_secs(10); // Without STATS_INTERVAL_SECS
+
+use scooper::config::{ServerConfig, RELOADABLE_SETTINGS};
+use scooper::error::ScooperError;
+use scooper::log_reader::{
+    compact_log, compress_archive, compression_of, is_finished_compressed, open_log, summarize_log,
+    CompactReport, LogReader,
+};
+use scooper::{
+    archive_path, bind_listener, client_field, compressed_path, detect_framing, fd_usage,
+    format_for, format_path, human_readable_duration, human_readable_size, increment_bytes_counter,
+    ingest, now, parse_proxy_header, persist, render_prometheus, render_statsd, reverse_dns,
+    run_marker, shard_for, shard_path, stats_line, strip_checksum, take_frames, time_to_full,
+    BatchedLog, CompressAlgo, DedupCache, DedupMode, DropReason, EmptyMessage, EntryOptions,
+    EntrySink, FormatRule, FrameError, Framing, IngestChecksum, IngestCompress, LogFormat, Message,
+    Metrics, OnFull, OpenMode, RecordSeparator, SanitizeMode, ShardPolicy, StampMode, WriteOutcome,
+    PROXY_V1_MAX_LEN, PROXY_V2_SIGNATURE, RUN_START, RUN_STOP,
+};
+use upstream::Upstream;
+
+#[derive(Debug, Clone)]
+struct ConnectionOptions {
+    compress: bool,
+    dedup: Option<Arc<std::sync::Mutex<DedupCache>>>,
+    format_map: Arc<[FormatRule]>,
+    formats: Arc<[LogFormat]>, // The formats that have log files, `LOG_FORMAT` first
+    // The entry options of each of `formats`, swapped by `reload_on_signal`
+    entries: Arc<RwLock<Arc<[EntryOptions]>>>,
+    shard_policy: ShardPolicy,
+    shard_loads: Arc<[AtomicUsize]>, // Open connections per log file
+    next_shard: Arc<AtomicUsize>,    // For `SHARD_POLICY=round_robin`
+    recv_buffer_bytes: usize,
+    on_full: OnFull,
+    log_full: Arc<AtomicBool>, // Set once a message didn't fit, for `ON_FULL=drain`
+    rotation: Option<Arc<LogRotation>>,
+    keep_alive: bool,
+    framing: Framing,
+    ingest_checksum: IngestChecksum,
+    empty_message: EmptyMessage,
+    ingest_compress: IngestCompress,
+    proxy_protocol: bool,
+    max_connections: u64,
+    busy_message: Arc<[u8]>,
+    reverse_dns: bool,
+    max_client_field_len: usize,
+    min_bytes_per_sec: u64,
+    buffer_bytes: usize,
+    buffer_time: Duration,
+    write_stall: Option<Duration>, // WRITE_STALL_MS
+    upstreams: Arc<[Upstream]>,
+}
+
+impl ConnectionOptions {
+    /// The format of a client's records, from `FORMAT_MAP` or else `LOG_FORMAT`.
+    fn format_of(&self, client: &SocketAddr) -> LogFormat {
+        format_for(&self.format_map, client.ip(), self.formats[0])
+    }
+
+    /// Where the format of a client's records is in `formats` and `entries`.
+    fn format_index(&self, client: &SocketAddr) -> usize {
+        let format = self.format_of(client);
+        self.formats.iter().position(|&f| f == format).unwrap_or(0)
+    }
+
+    /// The current entry options, connections pick up a reload with their next message.
+    fn entries(&self) -> Arc<[EntryOptions]> {
+        Arc::clone(&self.entries.read().unwrap_or_else(PoisonError::into_inner))
+    }
+}
+
+/// Counts the bytes written through it, i.e. the compressed size of a compressed log.
+struct CountingWriter<W> {
+    inner: W,
+    written: usize,
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for CountingWriter<W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = poll {
+            self.written += n;
+        }
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Where a log's bytes go, `COMPRESS_LOG` writes them through a gzip or zstd encoder. Every
+/// flush of a compressed log ends a block, so readers can decompress everything up to it.
+enum LogSink {
+    Plain(BufWriter<File>),
+    Gzip(GzipEncoder<CountingWriter<File>>, u32), // With its COMPRESS_LEVEL
+    Zstd(ZstdEncoder<CountingWriter<File>>, u32),
+    #[cfg(unix)]
+    Fifo(fifo::FifoSink),
+}
+
+impl LogSink {
+    /// A plain log, or one compressed with a codec and its `COMPRESS_LEVEL`.
+    fn new(file: File, compression: Option<(CompressAlgo, u32)>) -> Self {
+        let counting = |inner| CountingWriter { inner, written: 0 };
+        match compression {
+            Some((CompressAlgo::Gzip, level)) => Self::Gzip(
+                GzipEncoder::with_quality(counting(file), Level::Precise(level as i32)),
+                level,
+            ),
+            Some((CompressAlgo::Zstd, level)) => Self::Zstd(
+                ZstdEncoder::with_quality(counting(file), Level::Precise(level as i32)),
+                level,
+            ),
+            Some((CompressAlgo::None, _)) | None => Self::Plain(BufWriter::new(file)),
+        }
+    }
+
+    fn compression(&self) -> Option<(CompressAlgo, u32)> {
+        match self {
+            Self::Gzip(_, level) => Some((CompressAlgo::Gzip, *level)),
+            Self::Zstd(_, level) => Some((CompressAlgo::Zstd, *level)),
+            _ => None,
+        }
+    }
+
+    async fn write_all(&mut self, data: &[u8]) -> io::Result<()> {
+        match self {
+            Self::Plain(file) => file.write_all(data).await,
+            Self::Gzip(encoder, _) => encoder.write_all(data).await,
+            Self::Zstd(encoder, _) => encoder.write_all(data).await,
+            #[cfg(unix)]
+            Self::Fifo(fifo) => fifo.write_all(data).await,
+        }
+    }
+
+    async fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Plain(file) => file.flush().await,
+            Self::Gzip(encoder, _) => encoder.flush().await,
+            Self::Zstd(encoder, _) => encoder.flush().await,
+            #[cfg(unix)]
+            Self::Fifo(_) => Ok(()), // Nothing is buffered
+        }
+    }
+
+    /// Flushes everything, ending the stream of a compressed log.
+    async fn finish(&mut self) -> io::Result<()> {
+        match self {
+            Self::Plain(file) => file.flush().await,
+            Self::Gzip(encoder, _) => encoder.shutdown().await,
+            Self::Zstd(encoder, _) => encoder.shutdown().await,
+            #[cfg(unix)]
+            Self::Fifo(_) => Ok(()),
+        }
+    }
+}
+
+struct LogWriter {
+    file: LogSink,
+    format: LogFormat,
+    metrics: Arc<Metrics>,
+    flush_every_write: bool,
+    pending: Vec<Instant>, // Receive times of entries written since the last flush
+    #[cfg(test)]
+    flushes: usize,
+    dirty: bool, // Whether anything was written since the last flush, e.g. a run marker
+    counted: usize, // Bytes of the current file counted against MAX_FILE_SIZE
+    write_stall: Option<Duration>,
+    entries: u64,     // Entries in the current file, for MAX_ENTRIES_PER_FILE
+    max_entries: u64, // 0 is no limit
+    path: PathBuf,    // Where the current file is
+    // The codec of rotated archives, `None` if they're compressed already or left as they are
+    archive_compression: Option<(CompressAlgo, u32)>,
+    compressing: Vec<JoinHandle<()>>, // Archives that are still being compressed
+    leader: &'static [u8],            // What entries start with, see `Formatter::leader`
+    at_start: bool,                   // Whether nothing is in the current file yet
+}
+
+impl LogWriter {
+    fn new(
+        file: LogSink,
+        counted: usize,
+        format: LogFormat,
+        metrics: Arc<Metrics>,
+        flush_every_write: bool,
+        write_stall: Option<Duration>,
+        leader: &'static [u8],
+    ) -> Self {
+        Self {
+            file,
+            format,
+            metrics,
+            flush_every_write,
+            pending: Vec::new(),
+            #[cfg(test)]
+            flushes: 0,
+            dirty: false,
+            at_start: counted == 0,
+            counted,
+            write_stall,
+            entries: 0,
+            max_entries: 0,
+            path: PathBuf::new(),
+            archive_compression: None,
+            compressing: Vec::new(),
+            leader,
+        }
+    }
+
+    /// Where the file is, its rotations and archives are named after it.
+    fn with_path(self, path: PathBuf) -> Self {
+        Self { path, ..self }
+    }
+
+    /// Compresses the archives of rotated files with a codec and its `COMPRESS_LEVEL`.
+    fn with_archive_compression(self, compression: Option<(CompressAlgo, u32)>) -> Self {
+        Self {
+            archive_compression: compression,
+            ..self
+        }
+    }
 
-const DEFAULT_PORT: u16 = 8001;
-const DEFAULT_LOG_FILE: &str = "messages.log";
-const DEFAULT_MAX_LOG_SIZE: usize = 50 * 1024 * 1024; // 50 MB
+    /// Archives the file once it holds `max` entries, `existing` are in it already.
+    fn with_max_entries(self, max: u64, existing: u64) -> Self {
+        self.metrics.file_entries_written(existing);
+        Self {
+            entries: existing,
+            max_entries: max,
+            ..self
+        }
+    }
 
-use scooper::{human_readable_size, now, parsable_env_var};
+    /// Warns if `what`, started at `started` (before waiting for this writer), took longer
+    /// than WRITE_STALL_MS.
+    fn check_stall(&self, what: impl FnOnce() -> String, started: Instant) {
+        check_stall(self.write_stall, &self.metrics, what, started);
+    }
+
+    fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    async fn flush(&mut self) -> io::Result<()> {
+        self.file.flush().await?;
+        self.dirty = false;
+        #[cfg(test)]
+        {
+            self.flushes += 1;
+        }
+        for received in self.pending.drain(..) {
+            self.metrics.flush_completed(received);
+        }
+        Ok(())
+    }
 
-async fn increment_bytes_counter(bytes_counter: &Mutex<usize>, n: usize, max_size: usize) -> bool {
-    let mut bytes_guard = bytes_counter.lock().await;
-    if *bytes_guard > max_size {
+    /// Flushes the log for the last time, no more entries can be written after this.
+    async fn finish(&mut self) -> io::Result<()> {
+        self.flush().await?;
+        self.file.finish().await
+    }
+
+    /// Switches to a new file, e.g. after the old one was replaced on disk.
+    async fn reopen(&mut self, path: &Path) -> io::Result<()> {
+        self.finish().await?;
+        self.open(path).await
+    }
+
+    /// Finishes the current file and continues in `file` at `path`, which holds `existing`
+    /// entries of `format` already, after a reload changed `LOG_FORMAT`. The switch happens
+    /// even if finishing fails, the old file can't take any more entries either way.
+    async fn switch_format(
+        &mut self,
+        file: File,
+        path: PathBuf,
+        format: LogFormat,
+        leader: &'static [u8],
+        existing: u64,
+    ) -> io::Result<()> {
+        let finished = self.finish().await;
+        self.metrics
+            .file_entries_archived(std::mem::replace(&mut self.entries, existing));
+        self.metrics.file_entries_written(existing);
+        self.at_start = file.metadata().await.is_ok_and(|m| m.len() == 0);
+        self.file = LogSink::new(file, self.file.compression());
+        self.format = format;
+        self.leader = leader;
+        self.path = path;
+        finished
+    }
+
+    /// Moves the log to `archive` and starts a new one at `path`, returning the bytes of
+    /// the archived file that were counted against MAX_FILE_SIZE.
+    async fn rotate(&mut self, path: &Path, archive: &Path) -> io::Result<usize> {
+        self.finish().await?;
+        let counted = std::mem::take(&mut self.counted);
+        self.take_written(); // The end of the archived gzip stream was never counted
+        self.counted = 0;
+        self.metrics
+            .file_entries_archived(std::mem::take(&mut self.entries));
+        fs::rename(path, archive).await?;
+        self.open(path).await?;
+        if let Some((algo, level)) = self.archive_compression {
+            self.compressing
+                .retain(|compressing| !compressing.is_finished());
+            let compressing = compress_in_background(archive.to_path_buf(), algo, level);
+            self.compressing.push(compressing);
+        }
+        Ok(counted)
+    }
+
+    /// Archives the file if `n` more entries would take it past MAX_ENTRIES_PER_FILE, returning
+    /// the bytes of the archived file that were counted against MAX_FILE_SIZE. A batch is
+    /// never split, so one that's bigger than the limit gets a file of its own.
+    async fn rotate_if_entries_full(&mut self, n: usize) -> io::Result<usize> {
+        if self.max_entries == 0 || self.entries == 0 || self.entries + n as u64 <= self.max_entries
+        {
+            return Ok(0);
+        }
+        let path = self.path.clone();
+        let mut timestamp = now();
+        while archive_path(&path, timestamp).exists() {
+            timestamp += 1; // Another rotation within the same millisecond
+        }
+        let archive = archive_path(&path, timestamp);
+        let entries = self.entries;
+        let counted = self.rotate(&path, &archive).await?;
+        println!(
+            "Reached MAX_ENTRIES_PER_FILE with {entries} entries, rotated {} to {}",
+            path.display(),
+            archive.display()
+        );
+        Ok(counted)
+    }
+
+    async fn open(&mut self, path: &Path) -> io::Result<()> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await?;
+        self.at_start = file.metadata().await?.len() == 0;
+        self.file = LogSink::new(file, self.file.compression());
+        Ok(())
+    }
+}
+
+/// Compresses a rotated archive on a blocking thread, see `compress_archive`.
+fn compress_in_background(archive: PathBuf, algo: CompressAlgo, level: u32) -> JoinHandle<()> {
+    tokio::task::spawn_blocking(move || match compress_archive(&archive, algo, level) {
+        Ok(compressed) => println!(
+            "Compressed {} to {}",
+            archive.display(),
+            compressed.display()
+        ),
+        Err(e) => eprintln!("Failed to compress {}: {e}", archive.display()),
+    })
+}
+
+/// The log files of all the shards, for `ON_FULL=rotate`.
+struct LogRotation {
+    shards: LogShards,
+}
+
+impl std::fmt::Debug for LogRotation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LogRotation")
+            .field("shards", &self.shards.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl LogRotation {
+    /// Archives every shard that has anything in it (unless another connection rotated
+    /// already), so `n` more bytes fit. Returns `false` if they don't, because other
+    /// connections counted bytes that they haven't written yet, and will write to the new
+    /// files. The bytes are counted as they're written, see `persist`.
+    async fn rotate(
+        &self,
+        bytes_counter: &Mutex<usize>,
+        n: usize,
+        max_size: usize,
+    ) -> io::Result<bool> {
+        let mut writers = Vec::with_capacity(self.shards.len());
+        for file in self.shards.iter() {
+            writers.push(file.lock().await);
+        }
+        let mut counter = bytes_counter.lock().await;
+        if *counter + n <= max_size {
+            return Ok(true);
+        }
+        let mut rotated = 0;
+        let mut timestamp = now();
+        // Rotations within the same millisecond mustn't overwrite each other
+        while writers
+            .iter()
+            .any(|writer| archive_path(&writer.path, timestamp).exists())
+        {
+            timestamp += 1;
+        }
+        for writer in writers.iter_mut() {
+            if writer.counted == 0 {
+                continue;
+            }
+            let path = writer.path.clone();
+            let archive = archive_path(&path, timestamp);
+            rotated += writer.rotate(&path, &archive).await?;
+            println!(
+                "Log is full, rotated {} to {}",
+                path.display(),
+                archive.display()
+            );
+        }
+        *counter = counter.saturating_sub(rotated);
+        // Nothing else is pending if the counter is back at 0, so even a batch that's
+        // bigger than a whole file goes in
+        Ok(*counter + n <= max_size || *counter == 0)
+    }
+}
+
+impl EntrySink for LogWriter {
+    async fn write_entries(
+        &mut self,
+        data: &[u8],
+        received: &[Instant],
+        payload_bytes: usize,
+    ) -> io::Result<()> {
+        let data = match self.at_start {
+            true => data.strip_prefix(self.leader).unwrap_or(data),
+            false => data,
+        };
+        self.file.write_all(data).await?;
+        self.at_start &= data.is_empty();
+        self.dirty |= !data.is_empty();
+        if !self.is_compressed() {
+            self.counted += payload_bytes;
+        }
+        self.entries += received.len() as u64;
+        self.metrics.file_entries_written(received.len() as u64);
+        self.pending.extend_from_slice(received);
+        if self.flush_every_write {
+            self.flush().await?;
+        }
+        Ok(())
+    }
+
+    async fn make_room(&mut self, n: usize) -> io::Result<usize> {
+        self.rotate_if_entries_full(n).await
+    }
+
+    fn is_compressed(&self) -> bool {
+        matches!(self.file, LogSink::Gzip(..) | LogSink::Zstd(..))
+    }
+
+    /// Always 0 for uncompressed logs, whose size is counted in payload bytes as they're
+    /// logged.
+    fn take_written(&mut self) -> usize {
+        let written = match &mut self.file {
+            LogSink::Plain(_) => 0,
+            #[cfg(unix)]
+            LogSink::Fifo(_) => 0,
+            LogSink::Gzip(encoder, _) => std::mem::take(&mut encoder.get_mut().written),
+            LogSink::Zstd(encoder, _) => std::mem::take(&mut encoder.get_mut().written),
+        };
+        self.counted += written;
+        written
+    }
+}
+
+/// Warns if `what`, started at `started` (before waiting for the log), took longer than
+/// WRITE_STALL_MS.
+fn check_stall(
+    threshold: Option<Duration>,
+    metrics: &Metrics,
+    what: impl FnOnce() -> String,
+    started: Instant,
+) {
+    let Some(threshold) = threshold else {
+        return;
+    };
+    let elapsed = started.elapsed();
+    if elapsed > threshold {
         eprintln!(
-            "File size exceeds the limit of {} | Exiting...",
-            human_readable_size(max_size)
+            "Warning: {} took {elapsed:?}, more than WRITE_STALL_MS ({threshold:?})",
+            what()
         );
-        exit(1);
-    } else {
-        *bytes_guard += n;
+        metrics.write_stalled();
+    }
+}
+
+type SharedLog = Arc<Mutex<LogWriter>>;
+type LogShards = Arc<Vec<SharedLog>>;
+
+/// A connection's average receive rate, for `MIN_BYTES_PER_SEC`.
+struct Throughput {
+    started: Instant,
+    bytes: u64,
+    min_bytes_per_sec: u64,
+}
+
+impl Throughput {
+    fn new(min_bytes_per_sec: u64) -> Self {
+        Self {
+            started: Instant::now(),
+            bytes: 0,
+            min_bytes_per_sec,
+        }
+    }
+
+    /// When the average rate falls below the minimum if nothing else arrives.
+    fn deadline(&self) -> Option<Instant> {
+        if self.min_bytes_per_sec == 0 {
+            return None;
+        }
+        let allowed = Duration::from_secs_f64(self.bytes as f64 / self.min_bytes_per_sec as f64);
+        Some(self.started + allowed.max(SLOW_CLIENT_GRACE))
+    }
+}
+
+/// Logs the messages of a single connection.
+struct ConnectionLog<'a> {
+    log: BatchedLog<LogWriter>,
+    client: &'a SocketAddr,
+    client_field: String,
+    format: usize, // See `ConnectionOptions::format_index`
+    max_size: usize,
+    options: &'a ConnectionOptions,
+}
+
+impl ConnectionLog<'_> {
+    async fn log(&mut self, message: &[u8], received: Instant) -> Result<(), ScooperError> {
+        let (client, options) = (self.client, self.options);
+        let n_fmt = human_readable_size(message.len());
+        if options.on_full == OnFull::Drain && options.log_full.load(Ordering::Relaxed) {
+            println!("Log is full, discarded {n_fmt} from {client}");
+            self.log.metrics.message_dropped(DropReason::LogFull);
+            self.write_batch().await?;
+            return Err(ScooperError::LogFull {
+                limit: self.max_size,
+            });
+        }
+        println!("Received {n_fmt} from {client}");
+        let entries = options.entries();
+        let entry = &entries[self.format];
+        let dedup = options.dedup.as_deref();
+        let message = Message::new(message, dedup, entry);
+        // A duplicate is logged without its payload, but still forwarded in full
+        if !self.reserve(message.logged_len(), &n_fmt).await? {
+            return Ok(());
+        }
+        let (messages, started) = (self.log.batch.len() + 1, Instant::now());
+        let outcome = ingest(
+            &mut self.log,
+            &self.client_field,
+            &message,
+            dedup,
+            entry,
+            received,
+        )
+        .await;
+        match outcome.map_err(disk_full)? {
+            // Only batched unless the batch is empty again
+            WriteOutcome::Written if self.log.batch.is_empty() => {
+                self.check_stall(messages, started)
+            }
+            WriteOutcome::Written => {}
+            WriteOutcome::WouldExceed => self.write_batch().await?,
+        }
+        if !options.upstreams.is_empty() {
+            // One shared buffer for all the upstreams
+            let shared = Bytes::copy_from_slice(&message.payload);
+            for upstream in options.upstreams.iter() {
+                upstream.send(shared.clone());
+            }
+        }
+        Ok(())
+    }
+
+    /// Makes room for `n` bytes according to `ON_FULL`. Returns `false` if the message has
+    /// to be dropped but the connection can carry on, and an error if the log is full.
+    async fn reserve(&mut self, n: usize, n_fmt: &str) -> Result<bool, ScooperError> {
+        let options = self.options;
+        if options.rotation.is_some() {
+            if n > self.max_size {
+                println!(
+                    "Message of {n_fmt} from {} can't fit in any log file, discarded",
+                    self.client
+                );
+                self.log.metrics.message_dropped(DropReason::Oversize);
+                return Ok(false);
+            }
+            return Ok(true); // Counted as it's written, see `persist`
+        }
+        // A compressed log counts the compressed bytes once they're written instead
+        let counted_size = if options.compress { 0 } else { n };
+        let fits =
+            increment_bytes_counter(&self.log.bytes_counter, counted_size, self.max_size).await;
+        if fits == WriteOutcome::Written {
+            return Ok(true);
+        }
+        println!("Log is full, discarded {n_fmt} from {}", self.client);
+        options.log_full.store(true, Ordering::Relaxed);
+        self.log.metrics.message_dropped(DropReason::LogFull);
+        self.write_batch().await?;
+        Err(ScooperError::LogFull {
+            limit: self.max_size,
+        })
+    }
+
+    async fn write_batch(&mut self) -> Result<(), ScooperError> {
+        if self.log.batch.is_empty() {
+            return Ok(());
+        }
+        let (messages, started) = (self.log.batch.len(), Instant::now());
+        // Only ON_FULL=rotate has a size limit for `persist`, which leaves the batch until
+        // the rotation makes room for it
+        while persist(&mut self.log).await.map_err(disk_full)? == WriteOutcome::WouldExceed {
+            let Some(rotation) = &self.options.rotation else {
+                break;
+            };
+            let size = match self.options.compress {
+                true => 0,
+                false => self.log.batch.payload_bytes(),
+            };
+            while !rotation
+                .rotate(&self.log.bytes_counter, size, self.max_size)
+                .await?
+            {
+                // Let the pending writes through before trying again
+                tokio::task::yield_now().await;
+            }
+        }
+        self.check_stall(messages, started);
+        Ok(())
+    }
+
+    fn check_stall(&self, messages: usize, started: Instant) {
+        check_stall(
+            self.options.write_stall,
+            &self.log.metrics,
+            || format!("Writing {messages} messages from {}", self.client),
+            started,
+        );
+    }
+}
+
+fn disk_full(e: io::Error) -> ScooperError {
+    match e.kind() {
+        io::ErrorKind::StorageFull => ScooperError::DiskFull(e),
+        _ => e.into(),
     }
-    true
-    // bytes_guard goes out of scope and releases the lock
 }
 
 async fn log_message(
-    file: Arc<Mutex<BufWriter<File>>>,
+    file: SharedLog,
     socket: &mut TcpStream,
     client: &SocketAddr,
     bytes_counter: Arc<Mutex<usize>>,
     max_size: usize,
-) -> io::Result<()> {
-    let mut reader = BufReader::new(socket);
-    let mut buffer = vec![0; 4096];
-    let n = match reader.read(&mut buffer).await {
-        Ok(n) if n > 0 => n,
-        Err(_) | Ok(_) => {
-            // An empty message or an error occurred, we flush what we have and return
-            file.lock().await.flush().await?;
-            return Ok(());
+    metrics: Arc<Metrics>,
+    options: &ConnectionOptions,
+) -> Result<(), ScooperError> {
+    let mut throughput = Throughput::new(options.min_bytes_per_sec);
+    let compressed = options.ingest_compress == IngestCompress::Gzip;
+    let mut framing = match options.framing {
+        // A compressed connection is detected once its first bytes are decompressed
+        Framing::Auto if !compressed => {
+            let mut peeked = [0; 64];
+            let peek = socket.peek(&mut peeked);
+            let n = match throughput.deadline() {
+                Some(deadline) => timeout_at(deadline.into(), peek).await.unwrap_or(Ok(0)),
+                None => peek.await,
+            };
+            detect_framing(&peeked[..n.unwrap_or(0)])
         }
+        framing => framing,
     };
-    let n_fmt = human_readable_size(n);
-    println!("Received {n_fmt} from {client}");
-    let line_stamp = format!("\n$$${}$$${}$$${n}$$$\n", now(), client);
-    {
-        let mut file_guard = file.lock().await;
-        file_guard.write_all(line_stamp.as_bytes()).await?;
-        file_guard.write_all(&buffer[0..n]).await?;
-        file_guard.flush().await?;
-        // file_guard goes out of scope and releases the lock
+    let identity = match options.reverse_dns {
+        true => client_name(client.ip()).await,
+        false => None,
+    };
+    let mut log = ConnectionLog {
+        log: BatchedLog {
+            bytes_counter,
+            size_limit: options.rotation.as_ref().map(|_| max_size),
+            buffer_bytes: options.buffer_bytes,
+            ..BatchedLog::new(file, metrics)
+        },
+        client,
+        client_field: client_field(identity.as_deref(), client, options.max_client_field_len),
+        format: options.format_index(client),
+        max_size,
+        options,
+    };
+    let mut reader: Pin<Box<dyn AsyncRead + Send + '_>> = match compressed {
+        true => {
+            let mut decoder = GzipDecoder::new(BufReader::new(socket));
+            decoder.multiple_members(true);
+            Box::pin(decoder)
+        }
+        false => Box::pin(BufReader::new(socket)),
+    };
+    let mut buffer = vec![0; 4096];
+    let mut pending = Vec::new();
+    let mut received_any = false;
+    'connection: loop {
+        let slow_deadline = throughput.deadline();
+        let deadline = log
+            .log
+            .batch
+            .deadline(options.buffer_time)
+            .into_iter()
+            .chain(slow_deadline)
+            .min();
+        let read = match deadline {
+            Some(deadline) => match timeout_at(deadline.into(), reader.read(&mut buffer)).await {
+                Ok(read) => read,
+                Err(_) if slow_deadline == Some(deadline) => {
+                    eprintln!(
+                        "Closing connection from {client}, it sent less than {} bytes/s",
+                        options.min_bytes_per_sec
+                    );
+                    break;
+                }
+                Err(_) => {
+                    log.write_batch().await?;
+                    continue;
+                }
+            },
+            None => reader.read(&mut buffer).await,
+        };
+        let n = match read {
+            Ok(n) if n > 0 => n,
+            Err(e) if compressed && e.kind() == io::ErrorKind::InvalidData => {
+                eprintln!("Closing connection from {client}: invalid compressed data: {e}");
+                log.log.metrics.message_dropped(DropReason::BadCompression);
+                break;
+            }
+            // Nothing was received, so there's nothing to write. Flushing here would let a
+            // flood of empty connections turn into a flood of flushes, the writes of other
+            // connections are flushed after every write or by the periodic flush anyway
+            Err(_) | Ok(_) if !received_any => return Ok(()),
+            Err(_) | Ok(_) => 0,
+        };
+        received_any = true;
+        throughput.bytes += n as u64;
+        let received = Instant::now();
+        pending.extend_from_slice(&buffer[..n]);
+        if framing == Framing::Auto {
+            framing = detect_framing(&pending);
+        }
+        let eof = n == 0;
+        let mut frames = match take_frames(framing, &mut pending, eof) {
+            Ok(frames) => frames,
+            Err(e) => {
+                eprintln!("Closing connection from {client}: {e}");
+                if matches!(e, FrameError::Oversize(_)) {
+                    log.log.metrics.message_dropped(DropReason::Oversize);
+                }
+                break;
+            }
+        };
+        let close = options.empty_message.apply(&mut frames);
+        for frame in frames {
+            // Only kept with EMPTY_MESSAGE=log, an empty message has no room for a checksum
+            if frame.is_empty() {
+                log.log(&frame, received).await?;
+                continue;
+            }
+            let Some(payload) = strip_checksum(options.ingest_checksum, framing, &frame) else {
+                eprintln!("Closing connection from {client}: checksum mismatch");
+                log.log.metrics.message_dropped(DropReason::BadChecksum);
+                break 'connection;
+            };
+            log.log(payload, received).await?;
+        }
+        if close {
+            eprintln!("Closing connection from {client}: empty message");
+            break;
+        }
+        // Only raw framing has a single message per connection, unless KEEP_ALIVE is set
+        if eof || (framing == Framing::Raw && !options.keep_alive) {
+            break;
+        }
+    }
+    // The client disconnected, write whatever it still has buffered
+    log.write_batch().await
+}
+
+/// Writes a run marker to every shard, these aren't messages so they skip all the counters.
+async fn write_run_marker(
+    shards: &LogShards,
+    kind: &str,
+    separator: RecordSeparator,
+    instance: Option<&str>,
+) {
+    for file in shards.iter() {
+        let mut writer = file.lock().await;
+        let formatter = writer.format.formatter(StampMode::Full, separator, &[]);
+        let marker = run_marker(kind, instance, &*formatter);
+        writer
+            .write_entries(&marker, &[], 0)
+            .await
+            .unwrap_or_else(|e| match e.kind() {
+                io::ErrorKind::NotConnected => {} // A FIFO without a reader, already reported
+                _ => eprintln!("Failed to write the {kind} marker: {e}"),
+            });
     }
-    increment_bytes_counter(bytes_counter.as_ref(), n, max_size).await;
-    Ok(())
 }
 
 async fn graceful_shutdown(
-    message: &str,
-    code: i32,
-    file: Arc<Mutex<BufWriter<File>>>,
+    shards: LogShards,
     bytes_counter: Arc<Mutex<usize>>,
     original_size: usize,
+    run_markers: Option<RecordSeparator>,
+    instance: Option<&str>,
+    metrics: &Metrics,
 ) {
-    file.lock().await.flush().await.unwrap_or_else(|e| {
-        eprintln!("Failed to flush log file: {e}");
-    });
-    println!("{message}");
+    if let Some(separator) = run_markers {
+        write_run_marker(&shards, RUN_STOP, separator, instance).await;
+    }
+    for file in shards.iter() {
+        let mut writer = file.lock().await;
+        writer.finish().await.unwrap_or_else(|e| {
+            eprintln!("Failed to flush log file: {e}");
+        });
+        *bytes_counter.lock().await += writer.take_written();
+        for compressing in writer.compressing.drain(..) {
+            compressing.await.unwrap_or_default();
+        }
+    }
     let total = *bytes_counter.lock().await;
     println!(
         "Total log size: {} | Written in this session: {}",
         human_readable_size(total),
-        human_readable_size(total - original_size)
+        human_readable_size(total.saturating_sub(original_size))
+    );
+    let snapshot = metrics.snapshot();
+    println!(
+        "Messages written in this session: {} | {}",
+        snapshot.messages_total,
+        snapshot.dropped_summary()
     );
-    exit(code);
 }
 
-#[tokio::main]
-async fn main() -> io::Result<()> {
-    let port = parsable_env_var("PORT", DEFAULT_PORT);
-    let log_file = env::var("LOG_FILE").unwrap_or(DEFAULT_LOG_FILE.to_string());
-    let max_log_size = parsable_env_var("MAX_FILE_SIZE", DEFAULT_MAX_LOG_SIZE);
+async fn flush_periodically(
+    file: SharedLog,
+    period: Duration,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    let mut ticker = interval(period);
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {}
+            _ = shutdown.changed() => break,
+        }
+        let started = Instant::now();
+        let mut file_guard = file.lock().await;
+        if file_guard.is_dirty() {
+            file_guard.flush().await.unwrap_or_else(|e| {
+                eprintln!("Failed to flush log file: {e}");
+            });
+            file_guard.check_stall(|| "A periodic flush".to_string(), started);
+        }
+    }
+    // One last flush so the final interval isn't lost
+    file.lock().await.flush().await.unwrap_or_else(|e| {
+        eprintln!("Failed to flush log file: {e}");
+    });
+}
 
-    let addr = format!("0.0.0.0:{port}");
-    let listener = TcpListener::bind(&addr).await?;
-    println!(
-        "Server listening on port {addr} and writing to {log_file} (max file size: {})",
-        human_readable_size(max_log_size)
+/// The archives of a log, e.g. `messages.log.1700000000000` for `messages.log`.
+fn archives_of(log_file: &Path) -> io::Result<Vec<PathBuf>> {
+    let prefix = format!(
+        "{}.",
+        log_file.file_name().unwrap_or_default().to_string_lossy()
     );
-    let raw_file = OpenOptions::new()
-        .create(true)
-        .write(true)
-        .append(true)
-        .open(log_file)
-        .await?;
-    let previous_bytes_written = raw_file.metadata().await?.len() as usize;
-    if previous_bytes_written > max_log_size {
-        eprintln!("File size exceeds the limit of {max_log_size} bytes | Exiting...");
-        exit(1);
+    let dir = match log_file.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let mut archives = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let is_archive = name.strip_prefix(&prefix).is_some_and(|suffix| {
+            let timestamp = suffix
+                .strip_suffix(".gz")
+                .or_else(|| suffix.strip_suffix(".zst"))
+                .unwrap_or(suffix);
+            !timestamp.is_empty() && timestamp.bytes().all(|b| b.is_ascii_digit())
+        });
+        if is_archive && entry.file_type()?.is_file() {
+            archives.push(entry.path());
+        }
     }
-    let bytes_counter = Arc::new(Mutex::new(previous_bytes_written));
-    let file = Arc::new(Mutex::new(BufWriter::new(raw_file)));
+    archives.sort();
+    Ok(archives)
+}
 
-    let file_close = Arc::clone(&file);
-    let bytes_close = Arc::clone(&bytes_counter);
-    tokio::spawn(async move {
-        let caught = ctrl_c().await;
-        let (message, code) = match caught {
-            Ok(_) => ("Ctrl+C received, shutting down server...".to_string(), 0),
-            Err(e) => (format!("Failed to listen for Ctrl+C: {e}"), 1),
+/// Drops the records of an archive older than `cutoff`. Compressed archives can't be
+/// rewritten in place, so they're only removed once all of their records are too old.
+/// Archives left without records are removed.
+fn expire_archive(path: &Path, cutoff: u128) -> io::Result<CompactReport> {
+    if compression_of(path)? == CompressAlgo::None {
+        let report = compact_log(path, cutoff)?;
+        if report.kept == 0 {
+            std::fs::remove_file(path)?;
+        }
+        return Ok(report);
+    }
+    let summary = summarize_log(open_log(path)?)?;
+    if summary.error.is_some() || summary.last_timestamp.is_some_and(|ts| ts >= cutoff) {
+        return Ok(CompactReport {
+            kept: summary.records,
+            ..CompactReport::default()
+        });
+    }
+    let reclaimed_bytes = std::fs::metadata(path)?.len();
+    std::fs::remove_file(path)?;
+    Ok(CompactReport {
+        kept: 0,
+        dropped: summary.records,
+        reclaimed_bytes,
+    })
+}
+
+/// The client entries in a log, run markers aren't counted against MAX_ENTRIES_PER_FILE.
+fn count_entries(path: &Path) -> io::Result<u64> {
+    let entries = LogReader::new(open_log(path)?)
+        .filter(|entry| entry.as_ref().is_ok_and(|e| e.run_marker().is_none()))
+        .count();
+    Ok(entries as u64)
+}
+
+async fn run_blocking<T: Send + 'static>(
+    work: impl FnOnce() -> io::Result<T> + Send + 'static,
+) -> io::Result<T> {
+    tokio::task::spawn_blocking(work)
+        .await
+        .unwrap_or_else(|e| Err(io::Error::other(e)))
+}
+
+/// Drops the records of an active log older than `cutoff`, holding its writer meanwhile.
+/// Compressed logs are left alone, their records expire once they're archived.
+async fn expire_active_log(
+    file: &SharedLog,
+    cutoff: u128,
+    bytes_counter: &Mutex<usize>,
+) -> io::Result<CompactReport> {
+    let mut writer = file.lock().await;
+    if writer.is_compressed() {
+        return Ok(CompactReport::default());
+    }
+    writer.flush().await?;
+    let path = writer.path.clone();
+    let log = path.clone();
+    let report = run_blocking(move || compact_log(&log, cutoff)).await?;
+    if report.dropped > 0 {
+        writer.reopen(&path).await?;
+        writer.counted = writer
+            .counted
+            .saturating_sub(report.reclaimed_bytes as usize);
+        let mut counter = bytes_counter.lock().await;
+        *counter = counter.saturating_sub(report.reclaimed_bytes as usize);
+    }
+    Ok(report)
+}
+
+/// Periodically rewrites the logs and their archives without the records older than
+/// `retention`. Each active log is rewritten while holding its writer, which then switches
+/// to the new file. `stamped` are the stamped files of `LOG_FORMAT`, which are expired like
+/// archives once a reload switched away from them.
+async fn enforce_retention(
+    shards: LogShards,
+    stamped: Vec<PathBuf>,
+    retention: Duration,
+    period: Duration,
+    bytes_counter: Arc<Mutex<usize>>,
+) {
+    let mut ticker = interval(period);
+    loop {
+        ticker.tick().await;
+        let cutoff = now().saturating_sub(retention.as_millis());
+        let mut total = CompactReport::default();
+        let mut logs = Vec::with_capacity(shards.len());
+        for file in shards.iter() {
+            let writer = file.lock().await;
+            // Only stamped records can be parsed to find the old ones
+            if writer.format == LogFormat::Stamped {
+                logs.push((Some(file), writer.path.clone()));
+            }
+        }
+        for path in stamped.iter().filter(|&path| path.exists()) {
+            if !logs.iter().any(|(_, active)| active == path) {
+                logs.push((None, path.clone()));
+            }
+        }
+        for (file, path) in &logs {
+            let report = match file {
+                Some(file) => expire_active_log(file, cutoff, &bytes_counter).await,
+                None => {
+                    let log = path.clone();
+                    run_blocking(move || expire_archive(&log, cutoff)).await
+                }
+            };
+            match report {
+                Ok(report) => {
+                    total.dropped += report.dropped;
+                    total.reclaimed_bytes += report.reclaimed_bytes;
+                }
+                Err(e) => eprintln!("Retention: failed to rewrite {}: {e}", path.display()),
+            }
+            let archives = match archives_of(path) {
+                Ok(archives) => archives,
+                Err(e) => {
+                    eprintln!(
+                        "Retention: failed to list the archives of {}: {e}",
+                        path.display()
+                    );
+                    continue;
+                }
+            };
+            for archive in archives {
+                let name = archive.display().to_string();
+                match run_blocking(move || expire_archive(&archive, cutoff)).await {
+                    Ok(report) => {
+                        total.dropped += report.dropped;
+                        total.reclaimed_bytes += report.reclaimed_bytes;
+                    }
+                    Err(e) => eprintln!("Retention: failed to rewrite {name}: {e}"),
+                }
+            }
+        }
+        println!(
+            "Retention: reclaimed {} records ({}) older than {}s",
+            total.dropped,
+            human_readable_size(total.reclaimed_bytes as usize),
+            retention.as_secs()
+        );
+    }
+}
+
+async fn serve_metrics(listener: TcpListener, metrics: Arc<Metrics>) -> io::Result<()> {
+    loop {
+        let (mut socket, client) = listener.accept().await?;
+        let metrics = Arc::clone(&metrics);
+        tokio::spawn(async move {
+            let mut buffer = vec![0; 1024];
+            let n = socket.read(&mut buffer).await.unwrap_or(0);
+            let request = String::from_utf8_lossy(&buffer[..n]);
+            let path = request.split_whitespace().nth(1).unwrap_or_default();
+            let (status, body) = if path == "/metrics" {
+                ("200 OK", render_prometheus(&metrics))
+            } else {
+                ("404 Not Found", String::new())
+            };
+            let response = format!(
+                "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            );
+            if let Err(e) = socket.write_all(response.as_bytes()).await {
+                eprintln!("Failed to serve metrics to {client}: {e}");
+            }
+            socket.shutdown().await.unwrap_or_default();
+        });
+    }
+}
+
+async fn push_statsd(target: String, period: Duration, metrics: Arc<Metrics>) {
+    let socket = match UdpSocket::bind("0.0.0.0:0").await {
+        Ok(socket) => socket,
+        Err(e) => {
+            eprintln!("Failed to create StatsD socket: {e}");
+            return;
+        }
+    };
+    let mut previous = metrics.snapshot();
+    let mut ticker = interval(period);
+    let mut last_error_log: Option<Instant> = None;
+    let mut suppressed_errors = 0;
+    ticker.tick().await; // The first tick completes immediately
+    loop {
+        ticker.tick().await;
+        let current = metrics.snapshot();
+        let payload = render_statsd(&current, &previous);
+        previous = current;
+        if let Err(e) = socket.send_to(payload.as_bytes(), &target).await {
+            if last_error_log.is_some_and(|t| t.elapsed() < STATSD_ERROR_LOG_INTERVAL) {
+                suppressed_errors += 1;
+                continue;
+            }
+            eprintln!("Failed to send StatsD metrics to {target}: {e} ({suppressed_errors} similar errors suppressed)");
+            last_error_log = Some(Instant::now());
+            suppressed_errors = 0;
+        }
+    }
+}
+
+/// The options of entries in each of `formats`, from the settings that a reload applies.
+fn entry_options(config: &ServerConfig, formats: &[LogFormat]) -> Arc<[EntryOptions]> {
+    formats
+        .iter()
+        .map(|format| EntryOptions {
+            formatter: format.formatter(
+                config.stamp,
+                config.record_separator,
+                config.separator.as_bytes(),
+            ),
+            instance: config.instance_tag.as_deref().map(Arc::from),
+            sanitize: match format {
+                LogFormat::Binary => SanitizeMode::None, // Its payloads are kept byte for byte
+                _ => config.sanitize,
+            },
+            delimiter: Arc::from(config.separator.as_str()),
+            human_size: config.stamp_human_size,
+            tag_content: config.tag_content,
+        })
+        .collect()
+}
+
+/// Swaps in the entry options of `config`. The shards of `LOG_FORMAT` move to the files of
+/// its new format while those of `FORMAT_MAP` keep theirs, a FIFO only changes formats. The
+/// new files are all opened first, so failing to open one leaves everything as it was.
+#[cfg(unix)]
+async fn apply_reload(
+    config: &ServerConfig,
+    options: &ConnectionOptions,
+    shards: &LogShards,
+) -> io::Result<()> {
+    let mut formats = options.formats.to_vec();
+    formats[0] = config.log_format;
+    let entries = entry_options(config, &formats);
+    let paths = log_paths_of(config, &formats);
+    let mut switches = Vec::new();
+    for (i, (file, path)) in shards.iter().zip(paths).enumerate() {
+        let format = i * formats.len() / shards.len();
+        let leader = entries[format].formatter.leader();
+        let format = formats[format];
+        let mut writer = file.lock().await;
+        if config.fifo_path().is_some() {
+            writer.format = format;
+            writer.leader = leader;
+            continue;
+        }
+        if writer.format == format {
+            continue;
+        }
+        drop(writer);
+        let opened = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .await
+            .map_err(|e| {
+                io::Error::new(e.kind(), format!("Failed to open {}: {e}", path.display()))
+            })?;
+        let countable = config.max_entries_per_file > 0
+            && config.stamp == StampMode::Full
+            && format == LogFormat::Stamped
+            && opened.metadata().await?.len() > 0;
+        let existing = match countable {
+            true => {
+                let path = path.clone();
+                run_blocking(move || count_entries(&path)).await?
+            }
+            false => 0,
+        };
+        switches.push((file, opened, path, format, leader, existing));
+    }
+    for (file, opened, path, format, leader, existing) in switches {
+        let name = path.display().to_string();
+        let mut writer = file.lock().await;
+        if let Err(e) = writer
+            .switch_format(opened, path, format, leader, existing)
+            .await
+        {
+            eprintln!("Failed to finish the log before switching to {name}: {e}");
+        }
+        println!(
+            "Switched LOG_FORMAT to {}, writing to {name}",
+            format.as_str()
+        );
+    }
+    *options
+        .entries
+        .write()
+        .unwrap_or_else(PoisonError::into_inner) = entries;
+    Ok(())
+}
+
+/// Reloads the settings on every `SIGHUP`, from the config file and command line flags (the
+/// environment of a running process can't change). Only [`RELOADABLE_SETTINGS`] are
+/// applied, changes to the others are reported as requiring a restart.
+#[cfg(unix)]
+async fn reload_on_signal(running: ServerConfig, options: ConnectionOptions, shards: LogShards) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut signals = match signal(SignalKind::hangup()) {
+        Ok(signals) => signals,
+        Err(e) => {
+            eprintln!("Failed to listen for SIGHUP: {e}");
+            return;
+        }
+    };
+    let args: Vec<String> = env::args().skip(1).collect();
+    while signals.recv().await.is_some() {
+        let config = match ServerConfig::load(&args) {
+            Ok(config) => config,
+            Err(e) => {
+                eprintln!("Reload failed, keeping the current settings: {e}");
+                continue;
+            }
+        };
+        for setting in running.restart_only_changes(&config) {
+            eprintln!("Warning: the new value of {setting} requires a restart, ignoring it");
+        }
+        if let Err(e) = apply_reload(&running.reloaded(&config), &options, &shards).await {
+            eprintln!("Reload failed, keeping the current settings: {e}");
+            continue;
+        }
+        println!("Reloaded {}", RELOADABLE_SETTINGS.join(", "));
+    }
+}
+
+/// Prints a status summary to stderr on every `SIGUSR1`.
+#[cfg(unix)]
+async fn dump_status_on_signal(
+    bytes_counter: Arc<Mutex<usize>>,
+    original_size: usize,
+    max_size: usize,
+    metrics: Arc<Metrics>,
+) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut signals = match signal(SignalKind::user_defined1()) {
+        Ok(signals) => signals,
+        Err(e) => {
+            eprintln!("Failed to listen for SIGUSR1: {e}");
+            return;
+        }
+    };
+    while signals.recv().await.is_some() {
+        let total = *bytes_counter.lock().await;
+        let snapshot = metrics.snapshot();
+        eprintln!(
+            "Status | total log size: {} | written in this session: {} | messages: {} | active connections: {} | max file size: {}",
+            human_readable_size(total),
+            human_readable_size(total.saturating_sub(original_size)),
+            snapshot.messages_total,
+            snapshot.connections_active,
+            human_readable_size(max_size)
+        );
+    }
+}
+
+/// Projects when the log reaches `max_size` at the current rate every `period`, and warns
+/// when that's less than `threshold` away. Only crossing the threshold is reported, in
+/// either direction.
+async fn warn_before_full(
+    bytes_counter: Arc<Mutex<usize>>,
+    max_size: usize,
+    threshold: Duration,
+    period: Duration,
+    metrics: Arc<Metrics>,
+) {
+    let mut ticker = interval(period);
+    let mut warned = false;
+    loop {
+        ticker.tick().await;
+        let size = *bytes_counter.lock().await;
+        let projected = time_to_full(size, max_size, metrics.snapshot().bytes_per_sec);
+        metrics.set_time_to_full(projected);
+        match projected {
+            Some(left) if left < threshold && !warned => {
+                eprintln!(
+                    "Warning: the log is projected to reach its max file size of {} in {} at the current rate ({} of it used)",
+                    human_readable_size(max_size),
+                    human_readable_duration(left.as_secs()),
+                    human_readable_size(size)
+                );
+                warned = true;
+            }
+            Some(left) if left < threshold => {}
+            _ if warned => {
+                println!("The log is no longer projected to fill up within FULL_WARN_SECS");
+                warned = false;
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Waits for the open connections to finish, up to `timeout`.
+async fn drain_connections(metrics: &Metrics, timeout: Duration) -> Result<(), ScooperError> {
+    let started = Instant::now();
+    loop {
+        let open = metrics.snapshot().connections_active;
+        if open == 0 {
+            return Ok(());
+        }
+        if started.elapsed() >= timeout {
+            return Err(ScooperError::ShutdownTimeout { open });
+        }
+        sleep(DRAIN_POLL_INTERVAL).await;
+    }
+}
+
+/// Sets `SO_RCVBUF` on a socket, warning (once) if the OS gives it less than requested.
+fn set_recv_buffer_size(socket: &TcpStream, bytes: usize) {
+    static CLAMP_WARNING: Once = Once::new();
+    let socket = SockRef::from(socket);
+    let effective = socket
+        .set_recv_buffer_size(bytes)
+        .and_then(|()| socket.recv_buffer_size());
+    match effective {
+        Ok(effective) if effective < bytes => CLAMP_WARNING.call_once(|| {
+            eprintln!(
+                "Warning: RECV_BUFFER_BYTES={bytes} was clamped by the OS to {effective} bytes"
+            );
+        }),
+        Ok(_) => {}
+        Err(e) => eprintln!("Failed to set the socket receive buffer size: {e}"),
+    }
+}
+
+/// Takes over a listening TCP socket passed down by the parent process, e.g. by systemd
+/// socket activation or the previous process of a restart.
+#[cfg(unix)]
+fn adopt_listener(fd: i32) -> io::Result<TcpListener> {
+    use std::os::unix::io::FromRawFd;
+    // SAFETY: F_GETFD only reads the descriptor flags
+    if unsafe { libc::fcntl(fd, libc::F_GETFD) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: the descriptor is open and was handed to this process to own
+    let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+    if SockRef::from(&listener).r#type()? != socket2::Type::STREAM {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "not a TCP socket",
+        ));
+    }
+    listener.set_nonblocking(true)?;
+    TcpListener::from_std(listener)
+}
+
+/// Counts a connection against the shard it writes to while it's open.
+struct ShardLoad {
+    loads: Arc<[AtomicUsize]>,
+    shard: usize,
+}
+
+impl Drop for ShardLoad {
+    fn drop(&mut self) {
+        self.loads[self.shard].fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// The log a client's messages go to, picked by `SHARD_POLICY`. Each format has its own
+/// set of shards, so every file holds a single format.
+fn log_for(
+    shards: &LogShards,
+    options: &ConnectionOptions,
+    client: &SocketAddr,
+) -> (SharedLog, ShardLoad) {
+    let per_format = shards.len() / options.formats.len();
+    let format = options.format_of(client);
+    let first = options
+        .formats
+        .iter()
+        .position(|&f| f == format)
+        .unwrap_or(0)
+        * per_format;
+    let loads = Arc::clone(&options.shard_loads);
+    let shard = first
+        + match options.shard_policy {
+            ShardPolicy::Hash => shard_for(&client.ip().to_string(), per_format),
+            ShardPolicy::RoundRobin => {
+                options.next_shard.fetch_add(1, Ordering::Relaxed) % per_format
+            }
+            ShardPolicy::LeastLoaded => (0..per_format)
+                .min_by_key(|&i| loads[first + i].load(Ordering::Relaxed))
+                .unwrap_or(0),
         };
-        graceful_shutdown(&message, code, file_close, bytes_close, previous_bytes_written).await;
+    loads[shard].fetch_add(1, Ordering::Relaxed);
+    (Arc::clone(&shards[shard]), ShardLoad { loads, shard })
+}
+
+/// Reads the PROXY protocol header off the start of a connection, without reading past it.
+async fn read_proxy_header(socket: &mut TcpStream) -> io::Result<Vec<u8>> {
+    let mut header = vec![0; 8];
+    socket.read_exact(&mut header).await?;
+    if header.starts_with(b"PROXY ") {
+        // A v1 header is a line, peeking first keeps the data after it on the socket
+        let mut peeked = [0; PROXY_V1_MAX_LEN];
+        while !header.ends_with(b"\n") {
+            let room = PROXY_V1_MAX_LEN.saturating_sub(header.len());
+            if room == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "PROXY v1 header is too long",
+                ));
+            }
+            let n = socket.peek(&mut peeked[..room]).await?;
+            if n == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            let take = peeked[..n]
+                .iter()
+                .position(|&b| b == b'\n')
+                .map_or(n, |i| i + 1);
+            let start = header.len();
+            header.resize(start + take, 0);
+            socket.read_exact(&mut header[start..]).await?;
+        }
+    } else if PROXY_V2_SIGNATURE.starts_with(&header) {
+        header.resize(16, 0);
+        socket.read_exact(&mut header[8..]).await?;
+        let len = u16::from_be_bytes([header[14], header[15]]) as usize;
+        header.resize(16 + len, 0);
+        socket.read_exact(&mut header[16..]).await?;
+    } else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "missing PROXY protocol header",
+        ));
+    }
+    Ok(header)
+}
+
+/// The client behind the proxy that `peer` is, from the PROXY protocol header. It's `peer`
+/// itself when the proxy doesn't say, e.g. for its own health checks.
+async fn proxied_client(socket: &mut TcpStream, peer: SocketAddr) -> io::Result<SocketAddr> {
+    let header = timeout_at(
+        (Instant::now() + PROXY_HEADER_TIMEOUT).into(),
+        read_proxy_header(socket),
+    )
+    .await
+    .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "no PROXY protocol header"))??;
+    let client =
+        parse_proxy_header(&header).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    Ok(client.unwrap_or(peer))
+}
+
+/// The host name of a client for REVERSE_DNS, looked up off the runtime's threads. A resolver
+/// that takes longer than `REVERSE_DNS_TIMEOUT` leaves the client without one.
+async fn client_name(ip: IpAddr) -> Option<String> {
+    let lookup = tokio::task::spawn_blocking(move || reverse_dns(ip));
+    timeout(REVERSE_DNS_TIMEOUT, lookup).await.ok()?.ok()?
+}
+
+/// Closes a connection over MAX_CONNECTIONS, after telling the client it's busy unless
+/// BUSY_MESSAGE is empty. A client that doesn't read gets `BUSY_WRITE_TIMEOUT` at most.
+fn reject_busy(mut socket: TcpStream, busy_message: Arc<[u8]>) {
+    tokio::spawn(async move {
+        if !busy_message.is_empty() {
+            let _ = timeout(BUSY_WRITE_TIMEOUT, socket.write_all(&busy_message)).await;
+        }
+        let _ = socket.shutdown().await;
     });
+}
 
+async fn accept_loop(
+    listener: Arc<TcpListener>,
+    shards: LogShards,
+    bytes_counter: Arc<Mutex<usize>>,
+    max_log_size: usize,
+    metrics: Arc<Metrics>,
+    options: ConnectionOptions,
+    fatal: mpsc::UnboundedSender<ScooperError>,
+) -> io::Result<()> {
+    let mut rejecting = false; // Whether the last connection was rejected, to warn only once
     loop {
-        let file = Arc::clone(&file);
-        let (mut socket, client) = listener.accept().await?;
+        let (mut socket, peer) = listener.accept().await?;
+        let Some(connection) = metrics.try_connection_opened(options.max_connections) else {
+            if !rejecting {
+                eprintln!(
+                    "Warning: MAX_CONNECTIONS ({}) are open, rejecting new connections",
+                    options.max_connections
+                );
+                rejecting = true;
+            }
+            reject_busy(socket, Arc::clone(&options.busy_message));
+            continue;
+        };
+        rejecting = false;
+        if options.recv_buffer_bytes > 0 {
+            set_recv_buffer_size(&socket, options.recv_buffer_bytes);
+        }
+        let shards = Arc::clone(&shards);
         let bytes_counter = Arc::clone(&bytes_counter);
+        let metrics = Arc::clone(&metrics);
+        let fatal = fatal.clone();
+        let options = options.clone();
         tokio::spawn(async move {
-            log_message(file, &mut socket, &client, bytes_counter, max_log_size)
-                .await
-                .unwrap_or_else(|e| {
-                    eprintln!("Failed to log message from {client}: {e}");
-                });
+            let _connection = connection;
+            let client = match options.proxy_protocol {
+                true => match proxied_client(&mut socket, peer).await {
+                    Ok(client) => client,
+                    Err(e) => {
+                        eprintln!("Rejecting connection from {peer}: {e}");
+                        let _ = socket.shutdown().await;
+                        return;
+                    }
+                },
+                false => peer,
+            };
+            let (file, _shard_load) = log_for(&shards, &options, &client);
+            let logged = log_message(
+                file,
+                &mut socket,
+                &client,
+                bytes_counter,
+                max_log_size,
+                metrics,
+                &options,
+            )
+            .await;
+            match logged {
+                Ok(()) => {}
+                Err(e) if e.is_log_full() => {
+                    let _ = fatal.send(e);
+                }
+                Err(e) => eprintln!("Failed to log message from {client}: {e}"),
+            }
             socket.shutdown().await.unwrap_or_else(|e| {
                 eprintln!("Failed to shutdown client socket {client}: {e}");
             });
         });
     }
 }
+
+/// Applies `ROTATE_ON_START` and `OPEN_MODE` to the log files (one per shard) and opens them,
+/// returning the files and the number of bytes each already holds.
+async fn open_log_files(
+    paths: &[PathBuf],
+    config: &ServerConfig,
+) -> io::Result<Vec<(File, usize)>> {
+    if config.rotate_on_start && config.open_mode == OpenMode::Append {
+        let mut existing_sizes = Vec::with_capacity(paths.len());
+        for path in paths {
+            let size = match fs::metadata(path).await {
+                Ok(metadata) => metadata.len() as usize,
+                Err(_) => 0,
+            };
+            existing_sizes.push(size);
+        }
+        let existing_size: usize = existing_sizes.iter().sum();
+        if existing_size > 0
+            && existing_size as f64 >= config.max_log_size as f64 * config.rotate_on_start_fraction
+        {
+            let timestamp = now();
+            for (path, size) in paths.iter().zip(existing_sizes) {
+                if size == 0 {
+                    continue;
+                }
+                let archive = archive_path(path, timestamp);
+                fs::rename(path, &archive).await?;
+                println!(
+                    "Rotated existing log ({}) to {}",
+                    human_readable_size(size),
+                    archive.display()
+                );
+                if let Some((algo, level)) = archive_compression(config) {
+                    compress_in_background(archive, algo, level);
+                }
+            }
+        }
+    }
+    if config.compress_log && config.open_mode == OpenMode::Append {
+        // New data can't be appended to a compressed stream that was never finished
+        for path in paths {
+            let existing = fs::metadata(path).await.is_ok_and(|m| m.len() > 0);
+            let unfinished = existing && {
+                let log = path.clone();
+                !run_blocking(move || is_finished_compressed(&log)).await?
+            };
+            if unfinished {
+                let archive = archive_path(path, now());
+                fs::rename(path, &archive).await?;
+                println!(
+                    "Archived unfinished compressed log to {}",
+                    archive.display()
+                );
+            }
+        }
+    }
+    let mut open_options = OpenOptions::new();
+    match config.open_mode {
+        OpenMode::Append => open_options.create(true).append(true),
+        OpenMode::Truncate => open_options.create(true).write(true).truncate(true),
+        OpenMode::New => open_options.create_new(true).append(true),
+    };
+    let mut files = Vec::with_capacity(paths.len());
+    for path in paths {
+        let file = open_options.open(path).await.map_err(|e| {
+            io::Error::new(e.kind(), format!("Failed to open {}: {e}", path.display()))
+        })?;
+        let size = match config.open_mode {
+            OpenMode::Append => file.metadata().await?.len() as usize,
+            _ => 0,
+        };
+        files.push((file, size));
+    }
+    Ok(files)
+}
+
+/// The codec that `COMPRESS_ALGO` compresses rotated archives with, unless `COMPRESS_LOG`
+/// compresses the logs themselves.
+fn archive_compression(config: &ServerConfig) -> Option<(CompressAlgo, u32)> {
+    (!config.compress_log && config.compress_algo != CompressAlgo::None)
+        .then_some((config.compress_algo, config.compress_level))
+}
+
+/// The files of the shards of each of `formats`, in the order of `formats`.
+fn log_paths_of(config: &ServerConfig, formats: &[LogFormat]) -> Vec<PathBuf> {
+    if let Some(fifo) = config.fifo_path() {
+        return vec![fifo]; // FIFO logs can't be sharded, see `validate`
+    }
+    let mut paths = Vec::new();
+    for &format in formats {
+        let log_file = format_path(Path::new(&config.log_file), format);
+        let log_file = match config.compress_log {
+            true => compressed_path(&log_file, config.compress_algo),
+            false => log_file,
+        };
+        match config.write_shards {
+            0 | 1 => paths.push(log_file),
+            shards => paths.extend((0..shards).map(|shard| shard_path(&log_file, shard))),
+        }
+    }
+    paths
+}
+
+/// The options of every connection before the log files are open, see `run`.
+fn connection_options(config: &ServerConfig, formats: &[LogFormat]) -> ConnectionOptions {
+    ConnectionOptions {
+        compress: config.compress_log,
+        dedup: (config.dedup == DedupMode::Global && config.stamp == StampMode::Full).then(|| {
+            Arc::new(std::sync::Mutex::new(DedupCache::new(
+                config.dedup_window,
+                config.dedup_window_bytes,
+            )))
+        }),
+        format_map: config.format_map.as_slice().into(),
+        formats: formats.into(),
+        entries: Arc::new(RwLock::new(entry_options(config, formats))),
+        // A client's IP always hashes to the same shard, whichever connection it's on
+        shard_policy: match config.client_affinity {
+            true => ShardPolicy::Hash,
+            false => config.shard_policy,
+        },
+        shard_loads: Arc::new([]),
+        next_shard: Arc::new(AtomicUsize::new(0)),
+        recv_buffer_bytes: config.recv_buffer_bytes,
+        on_full: config.on_full,
+        log_full: Arc::new(AtomicBool::new(false)),
+        rotation: None,
+        keep_alive: config.keep_alive,
+        framing: config.framing,
+        ingest_checksum: config.ingest_checksum,
+        empty_message: config.empty_message,
+        ingest_compress: config.ingest_compress,
+        proxy_protocol: config.proxy_protocol,
+        max_connections: config.max_connections,
+        busy_message: config.busy_message.as_bytes().into(),
+        reverse_dns: config.reverse_dns,
+        max_client_field_len: config.max_client_field_len,
+        min_bytes_per_sec: config.min_bytes_per_sec,
+        buffer_bytes: config.connection_buffer_bytes,
+        buffer_time: Duration::from_millis(config.connection_buffer_ms),
+        write_stall: (config.write_stall_ms > 0)
+            .then(|| Duration::from_millis(config.write_stall_ms)),
+        upstreams: Arc::new([]),
+    }
+}
+
+async fn run(config: ServerConfig) -> Result<(), ScooperError> {
+    let mut formats = vec![config.log_format];
+    for rule in &config.format_map {
+        if !formats.contains(&rule.format) {
+            formats.push(rule.format);
+        }
+    }
+    let options = connection_options(&config, &formats);
+    let fifo_path = config.fifo_path();
+    // A FIFO doesn't fill up, what's written to it is gone once it's read
+    let max_log_size = match fifo_path {
+        Some(_) => usize::MAX,
+        None => config.max_log_size,
+    };
+    if config.ingest_checksum != IngestChecksum::None && config.framing == Framing::Raw {
+        eprintln!("Warning: INGEST_CHECKSUM only applies to lines and length framing, it's ignored with FRAMING=raw");
+    }
+    if config.client_affinity && config.shard_policy != ShardPolicy::Hash {
+        eprintln!("Warning: CLIENT_AFFINITY places clients by a hash of their IP, SHARD_POLICY is ignored");
+    }
+    if config.stamp == StampMode::None {
+        eprintln!(
+            "Warning: STAMP=none writes only the payloads, the log can't be parsed by the read, search or verify commands"
+        );
+        if config.dedup == DedupMode::Global {
+            eprintln!("Warning: DEDUP=global needs stamps to mark duplicates, it's ignored with STAMP=none");
+        }
+    }
+
+    let mut listeners = Vec::with_capacity(config.listen.len());
+    #[cfg(unix)]
+    for &fd in &config.listen_fds {
+        let listener = adopt_listener(fd).map_err(|e| {
+            ScooperError::Config(format!("Can't use the inherited listener fd {fd}: {e}"))
+        })?;
+        listeners.push(Arc::new(listener));
+    }
+    // Inherited listeners replace LISTEN, the sockets were bound by whoever passed them
+    if listeners.is_empty() {
+        for &addr in &config.listen {
+            let listener = bind_listener(addr, config.listen_backlog)
+                .map_err(|source| ScooperError::Bind { addr, source })?;
+            listeners.push(Arc::new(listener));
+        }
+    }
+    let addrs = listeners
+        .iter()
+        .map(|l| l.local_addr().map(|a| a.to_string()))
+        .collect::<io::Result<Vec<_>>>()?
+        .join(", ");
+    let log_paths = log_paths_of(&config, &formats);
+    let log_names = log_paths
+        .iter()
+        .map(|p| p.display().to_string())
+        .collect::<Vec<_>>()
+        .join(", ");
+    match fifo_path {
+        Some(_) => println!("Server listening on {addrs} and writing to FIFO {log_names}"),
+        None => println!(
+            "Server listening on {addrs} and writing to {log_names} (max file size: {})",
+            human_readable_size(max_log_size)
+        ),
+    }
+    let fds = fd_usage();
+    if let (Some(open), Some(soft_limit)) = (fds.open, fds.soft_limit) {
+        // None of these are open yet, only the listeners are
+        let others = log_paths.len() as u64
+            + u64::from(config.metrics_port > 0)
+            + u64::from(config.statsd_addr.is_some())
+            + config.upstream_addrs.len() as u64;
+        let (connections, cap) = match config.max_connections {
+            // Without a cap on connections, leave room for at least a full accept backlog
+            0 => (u64::from(config.listen_backlog), "LISTEN_BACKLOG"),
+            max => (max, "MAX_CONNECTIONS"),
+        };
+        if open + connections + others > soft_limit {
+            eprintln!(
+                "Warning: {open} file descriptors are open, {connections} more are needed for {cap} and {others} for the log files and sockets (RLIMIT_NOFILE soft limit: {soft_limit}), accepting connections will fail with EMFILE. Raise the limit with `ulimit -n`"
+            );
+        }
+    }
+    let sinks: Vec<(LogSink, usize)> = match &fifo_path {
+        #[cfg(unix)]
+        Some(path) => {
+            fifo::create(path)?;
+            vec![(LogSink::Fifo(fifo::FifoSink::new(path.clone())), 0)]
+        }
+        _ => {
+            if config.create_log_dir {
+                fs::create_dir_all(config.log_dir()).await?;
+            }
+            open_log_files(&log_paths, &config)
+                .await?
+                .into_iter()
+                .map(|(file, size)| {
+                    let compression = config
+                        .compress_log
+                        .then_some((config.compress_algo, config.compress_level));
+                    (LogSink::new(file, compression), size)
+                })
+                .collect()
+        }
+    };
+    let previous_bytes_written = sinks.iter().map(|(_, size)| size).sum();
+    if previous_bytes_written > max_log_size {
+        return Err(ScooperError::LogFull {
+            limit: max_log_size,
+        });
+    }
+    let bytes_counter = Arc::new(Mutex::new(previous_bytes_written));
+    let metrics = Arc::new(Metrics::new(config.rate_window_secs));
+    metrics.limit_file_entries(config.max_entries_per_file);
+    let mut existing_entries = Vec::with_capacity(sinks.len());
+    for (i, (_, size)) in sinks.iter().enumerate() {
+        // Only stamped logs can be read back to count what they already hold
+        let countable = config.max_entries_per_file > 0
+            && *size > 0
+            && config.stamp == StampMode::Full
+            && formats[i * formats.len() / log_paths.len()] == LogFormat::Stamped;
+        let existing = match countable {
+            true => {
+                let path = log_paths[i].clone();
+                run_blocking(move || count_entries(&path)).await?
+            }
+            false => 0,
+        };
+        existing_entries.push(existing);
+    }
+    let options = ConnectionOptions {
+        upstreams: config
+            .upstream_addrs
+            .iter()
+            .map(|addr| Upstream::spawn(addr.clone(), config.upstream_compress, &metrics))
+            .collect(),
+        ..options
+    };
+    let flush_interval = config.flush_interval_ms;
+    let shards: LogShards = Arc::new(
+        sinks
+            .into_iter()
+            .zip(existing_entries)
+            .enumerate()
+            .map(|(i, ((sink, size), existing))| {
+                let writer = LogWriter::new(
+                    sink,
+                    size,
+                    formats[i * formats.len() / log_paths.len()],
+                    Arc::clone(&metrics),
+                    flush_interval == 0,
+                    (config.write_stall_ms > 0)
+                        .then(|| Duration::from_millis(config.write_stall_ms)),
+                    options.entries()[i * formats.len() / log_paths.len()]
+                        .formatter
+                        .leader(),
+                );
+                let writer = writer
+                    .with_path(log_paths[i].clone())
+                    .with_archive_compression(archive_compression(&config));
+                let writer = match config.max_entries_per_file {
+                    0 => writer,
+                    max => writer.with_max_entries(max, existing),
+                };
+                Arc::new(Mutex::new(writer))
+            })
+            .collect(),
+    );
+    let options = ConnectionOptions {
+        shard_loads: shards.iter().map(|_| AtomicUsize::new(0)).collect(),
+        rotation: (config.on_full == OnFull::Rotate).then(|| {
+            Arc::new(LogRotation {
+                shards: Arc::clone(&shards),
+            })
+        }),
+        ..options
+    };
+    let run_markers =
+        (config.run_markers && config.stamp == StampMode::Full).then_some(config.record_separator);
+    if let Some(separator) = run_markers {
+        write_run_marker(
+            &shards,
+            RUN_START,
+            separator,
+            config.instance_tag.as_deref(),
+        )
+        .await;
+    }
+
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let mut flushers = Vec::new();
+    if flush_interval > 0 {
+        let period = Duration::from_millis(flush_interval);
+        for file in shards.iter() {
+            let flusher = flush_periodically(Arc::clone(file), period, shutdown_rx.clone());
+            flushers.push(tokio::spawn(flusher));
+        }
+    }
+
+    if config.retention_secs > 0 {
+        tokio::spawn(enforce_retention(
+            Arc::clone(&shards),
+            log_paths_of(&config, &[LogFormat::Stamped]),
+            Duration::from_secs(config.retention_secs),
+            Duration::from_secs(config.retention_interval_secs.max(1)),
+            Arc::clone(&bytes_counter),
+        ));
+    }
+
+    #[cfg(unix)]
+    tokio::spawn(reload_on_signal(
+        config.clone(),
+        options.clone(),
+        Arc::clone(&shards),
+    ));
+    #[cfg(unix)]
+    tokio::spawn(dump_status_on_signal(
+        Arc::clone(&bytes_counter),
+        previous_bytes_written,
+        max_log_size,
+        Arc::clone(&metrics),
+    ));
+
+    let stats_interval = config.stats_interval_secs;
+    if stats_interval > 0 {
+        let metrics = Arc::clone(&metrics);
+        tokio::spawn(async move {
+            let mut ticker = interval(Duration::from_secs(stats_interval));
+            ticker.tick().await; // The first tick completes immediately
+            loop {
+                ticker.tick().await;
+                println!("{}", stats_line(&metrics.snapshot()));
+            }
+        });
+    }
+    if config.full_warn_secs > 0 {
+        let period = match stats_interval {
+            0 => FULL_WARN_CHECK_INTERVAL,
+            secs => Duration::from_secs(secs),
+        };
+        tokio::spawn(warn_before_full(
+            Arc::clone(&bytes_counter),
+            max_log_size,
+            Duration::from_secs(config.full_warn_secs),
+            period,
+            Arc::clone(&metrics),
+        ));
+    }
+    if let Some(target) = config.statsd_addr.clone() {
+        let period = Duration::from_secs(config.statsd_interval_secs.max(1));
+        tokio::spawn(push_statsd(target, period, Arc::clone(&metrics)));
+    }
+
+    let mut accept_loops = JoinSet::new();
+    if config.metrics_port > 0 {
+        let addr = SocketAddr::from(([0, 0, 0, 0], config.metrics_port));
+        let listener = TcpListener::bind(addr)
+            .await
+            .map_err(|source| ScooperError::Bind { addr, source })?;
+        println!("Serving metrics on http://{addr}/metrics");
+        accept_loops.spawn(serve_metrics(listener, Arc::clone(&metrics)));
+    }
+    let (fatal_tx, mut fatal_rx) = mpsc::unbounded_channel();
+    // Several accept loops can share a listener, for connection-heavy workloads
+    for listener in listeners {
+        for _ in 0..config.accept_tasks.max(1) {
+            accept_loops.spawn(accept_loop(
+                Arc::clone(&listener),
+                Arc::clone(&shards),
+                Arc::clone(&bytes_counter),
+                max_log_size,
+                Arc::clone(&metrics),
+                options.clone(),
+                fatal_tx.clone(),
+            ));
+        }
+    }
+
+    let mut result = tokio::select! {
+        caught = ctrl_c() => caught
+            .map(|()| println!("Ctrl+C received, shutting down server..."))
+            .map_err(ScooperError::from),
+        Some(e) = fatal_rx.recv() => Err(e),
+        Some(joined) = accept_loops.join_next() => match joined {
+            Ok(result) => result.map_err(ScooperError::from),
+            Err(e) => Err(io::Error::other(e).into()),
+        },
+    };
+    accept_loops.shutdown().await;
+    if config.on_full == OnFull::Drain && result.as_ref().is_err_and(ScooperError::is_log_full) {
+        println!("Log is full, no longer accepting connections. Draining open connections...");
+        if let Err(e) = drain_connections(&metrics, DRAIN_TIMEOUT).await {
+            result = Err(e); // Connections were cut off, which matters more than the full log
+        }
+    }
+    let _ = shutdown_tx.send(true);
+    for flusher in flushers {
+        flusher.await.unwrap_or_default();
+    }
+    graceful_shutdown(
+        shards,
+        bytes_counter,
+        previous_bytes_written,
+        run_markers,
+        options.entries()[0].instance.as_deref(), // As of the last reload
+        &metrics,
+    )
+    .await;
+    result
+}
+
+fn serve(args: &[String]) -> Result<(), ScooperError> {
+    let config = ServerConfig::load(args)?;
+    if config.check_config {
+        config.check_addrs()?;
+        println!("{config:#?}");
+        println!("Config is valid");
+        return Ok(());
+    }
+    let mut builder = Builder::new_multi_thread();
+    builder.enable_all();
+    if config.worker_threads > 0 {
+        builder.worker_threads(config.worker_threads);
+    }
+    builder.build()?.block_on(run(config))
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if let Some(command) = args.first().filter(|arg| !arg.starts_with("--")) {
+        match commands::run(command, &args[1..]) {
+            Ok(code) => exit(code),
+            Err(e) if e.kind() == io::ErrorKind::BrokenPipe => exit(0), // e.g. piped into `head`
+            Err(e) => {
+                eprintln!("{e}");
+                exit(1);
+            }
+        }
+    }
+    if let Err(e) = serve(&args) {
+        eprintln!("{e} | Exiting...");
+        exit(e.exit_code());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use scooper::config::ConfigSource;
+
+    /// A connected pair of sockets on the loopback interface, the accepted one first.
+    async fn connected_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (accepted, _) = listener.accept().await.unwrap();
+        (accepted, client)
+    }
+
+    #[tokio::test]
+    async fn recv_buffer_size_is_applied() {
+        let (socket, _client) = connected_pair().await;
+        let default = SockRef::from(&socket).recv_buffer_size().unwrap();
+        // Smaller than the default so it can't be clamped, Linux doubles it for bookkeeping
+        let requested = default / 4;
+        set_recv_buffer_size(&socket, requested);
+        let effective = SockRef::from(&socket).recv_buffer_size().unwrap();
+        assert!(effective >= requested, "{effective} < {requested}");
+        assert_ne!(effective, default);
+    }
+
+    /// A writer to a fresh file at `path` that flushes only when asked.
+    async fn test_writer(path: &Path) -> LogWriter {
+        let file = File::create(path).await.unwrap();
+        let metrics = Arc::new(Metrics::default());
+        LogWriter::new(
+            LogSink::new(file, None),
+            0,
+            LogFormat::Stamped,
+            metrics,
+            false,
+            None,
+            b"",
+        )
+    }
+
+    const RECORD: &[u8] = b"\n$$$1$$$10.0.0.1:5000$$$2$$$\nhi";
+
+    /// A writer appending to `path` with leading separators, like the server opens its log.
+    async fn leading_writer(path: &Path) -> LogWriter {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await
+            .unwrap();
+        let size = file.metadata().await.unwrap().len() as usize;
+        let metrics = Arc::new(Metrics::default());
+        let leader = RecordSeparator::Leading.leader();
+        let sink = LogSink::new(file, None);
+        LogWriter::new(sink, size, LogFormat::Stamped, metrics, true, None, leader)
+            .with_path(path.to_path_buf())
+    }
+
+    fn test_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("scooper-{name}-{}.log", std::process::id()))
+    }
+
+    async fn entries_in(path: &Path) -> usize {
+        let data = fs::read(path).await.unwrap();
+        assert!(
+            data.starts_with(b"$$$"),
+            "{:?}",
+            String::from_utf8_lossy(&data)
+        );
+        LogReader::new(&data[..]).map(Result::unwrap).count()
+    }
+
+    #[tokio::test]
+    async fn fresh_file_starts_with_a_stamp() {
+        let path = test_path("fresh");
+        let _ = fs::remove_file(&path).await;
+        let mut writer = leading_writer(&path).await;
+        writer
+            .write_entries(RECORD, &[Instant::now()], 2)
+            .await
+            .unwrap();
+        writer
+            .write_entries(RECORD, &[Instant::now()], 2)
+            .await
+            .unwrap();
+        assert_eq!(
+            fs::read(&path).await.unwrap(),
+            [&RECORD[1..], RECORD].concat()
+        );
+        assert_eq!(entries_in(&path).await, 2);
+        fs::remove_file(&path).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn appended_file_keeps_the_separator() {
+        let path = test_path("appended");
+        fs::write(&path, &RECORD[1..]).await.unwrap();
+        let mut writer = leading_writer(&path).await;
+        writer
+            .write_entries(RECORD, &[Instant::now()], 2)
+            .await
+            .unwrap();
+        assert_eq!(
+            fs::read(&path).await.unwrap(),
+            [&RECORD[1..], RECORD].concat()
+        );
+        assert_eq!(entries_in(&path).await, 2);
+        fs::remove_file(&path).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn rotated_file_starts_with_a_stamp() {
+        let (first, second) = (test_path("rotated-1"), test_path("rotated-2"));
+        let _ = fs::remove_file(&second).await;
+        fs::write(&first, &RECORD[1..]).await.unwrap();
+        let mut writer = leading_writer(&first).await;
+        writer
+            .write_entries(RECORD, &[Instant::now()], 2)
+            .await
+            .unwrap();
+        writer.open(&second).await.unwrap();
+        writer
+            .write_entries(RECORD, &[Instant::now()], 2)
+            .await
+            .unwrap();
+        assert_eq!(fs::read(&second).await.unwrap(), &RECORD[1..]);
+        assert_eq!(entries_in(&first).await, 2);
+        assert_eq!(entries_in(&second).await, 1);
+        // Reopening a file that has records already keeps the separator
+        writer.open(&first).await.unwrap();
+        writer
+            .write_entries(RECORD, &[Instant::now()], 2)
+            .await
+            .unwrap();
+        assert_eq!(entries_in(&first).await, 3);
+        for path in [first, second] {
+            fs::remove_file(&path).await.unwrap();
+        }
+    }
+
+    fn records_in(path: &Path) -> usize {
+        let reader = open_log(path).unwrap();
+        LogReader::new(reader).map(Result::unwrap).count()
+    }
+
+    /// Writes a log compressed with `algo`, reading it back mid-stream and once finished.
+    async fn round_trip(algo: CompressAlgo) {
+        let plain = test_path(&format!("round-trip-{algo:?}"));
+        let path = compressed_path(&plain, algo);
+        let file = File::create(&path).await.unwrap();
+        let metrics = Arc::new(Metrics::default());
+        let compression = Some((algo, algo.default_level()));
+        let sink = LogSink::new(file, compression);
+        let leader = RecordSeparator::Leading.leader();
+        let mut writer = LogWriter::new(sink, 0, LogFormat::Stamped, metrics, true, None, leader);
+        assert_eq!(writer.is_compressed(), algo != CompressAlgo::None);
+        for _ in 0..3 {
+            writer
+                .write_entries(RECORD, &[Instant::now()], 2)
+                .await
+                .unwrap();
+        }
+        writer.flush().await.unwrap();
+        assert_eq!(compression_of(&path).unwrap(), algo);
+        assert_eq!(records_in(&path), 3);
+        assert_eq!(
+            is_finished_compressed(&path).unwrap(),
+            algo == CompressAlgo::None
+        );
+        writer
+            .write_entries(RECORD, &[Instant::now()], 2)
+            .await
+            .unwrap();
+        writer.finish().await.unwrap();
+        assert_eq!(records_in(&path), 4);
+        assert!(is_finished_compressed(&path).unwrap());
+        fs::remove_file(&path).await.unwrap();
+    }
+
+    /// Rotates a log on MAX_ENTRIES_PER_FILE with its archives compressed with `algo`,
+    /// reading the archive back once it's compressed.
+    async fn rotated_round_trip(algo: CompressAlgo) {
+        let path = test_path(&format!("rotated-{algo:?}"));
+        let _ = fs::remove_file(&path).await;
+        let mut writer = leading_writer(&path)
+            .await
+            .with_max_entries(2, 0)
+            .with_archive_compression(Some((algo, algo.default_level())));
+        for _ in 0..2 {
+            writer
+                .write_entries(RECORD, &[Instant::now()], 2)
+                .await
+                .unwrap();
+        }
+        writer.rotate_if_entries_full(1).await.unwrap();
+        for compressing in writer.compressing.drain(..) {
+            compressing.await.unwrap();
+        }
+        let archives = archives_of(&path).unwrap();
+        assert_eq!(archives.len(), 1, "{archives:?}");
+        let archive = &archives[0];
+        assert_eq!(archive, &compressed_path(archive, algo));
+        assert_eq!(compression_of(archive).unwrap(), algo);
+        assert!(is_finished_compressed(archive).unwrap());
+        assert_eq!(records_in(archive), 2);
+        assert_eq!(fs::metadata(&path).await.unwrap().len(), 0);
+        for path in [&path, archive] {
+            fs::remove_file(path).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn gzip_archive_round_trips() {
+        rotated_round_trip(CompressAlgo::Gzip).await;
+    }
+
+    #[tokio::test]
+    async fn zstd_archive_round_trips() {
+        rotated_round_trip(CompressAlgo::Zstd).await;
+    }
+
+    #[tokio::test]
+    async fn uncompressed_archive_round_trips() {
+        rotated_round_trip(CompressAlgo::None).await;
+    }
+
+    #[tokio::test]
+    async fn gzip_log_round_trips() {
+        round_trip(CompressAlgo::Gzip).await;
+    }
+
+    #[tokio::test]
+    async fn zstd_log_round_trips() {
+        round_trip(CompressAlgo::Zstd).await;
+    }
+
+    #[tokio::test]
+    async fn uncompressed_log_round_trips() {
+        round_trip(CompressAlgo::None).await;
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn reload_switches_the_log_format() {
+        let path = test_path("reload");
+        let json_path = format_path(&path, LogFormat::Json);
+        for path in [&path, &json_path] {
+            let _ = fs::remove_file(path).await;
+        }
+        let source = ConfigSource::default();
+        let config = ServerConfig {
+            log_file: path.display().to_string(),
+            ..ServerConfig::from_source(&source).unwrap()
+        };
+        let options = connection_options(&config, &[config.log_format]);
+        let writer = leading_writer(&path).await;
+        let shards: LogShards = Arc::new(vec![Arc::new(Mutex::new(writer))]);
+        let log = |message: &'static [u8]| {
+            let (options, shards) = (&options, &shards);
+            async move {
+                let metrics = Arc::new(Metrics::default());
+                let mut log = BatchedLog::new(Arc::clone(&shards[0]), metrics);
+                let entries = options.entries();
+                let message = Message::new(message, None, &entries[0]);
+                let client = "10.0.0.1:5000";
+                ingest(
+                    &mut log,
+                    client,
+                    &message,
+                    None,
+                    &entries[0],
+                    Instant::now(),
+                )
+                .await
+                .unwrap();
+                shards[0].lock().await.flush().await.unwrap();
+            }
+        };
+        log(b"hi").await;
+        let reloaded = ServerConfig {
+            log_format: LogFormat::Json,
+            ..config.clone()
+        };
+        apply_reload(&reloaded, &options, &shards).await.unwrap();
+        log(b"ho").await;
+        assert_eq!(shards[0].lock().await.path, json_path);
+        // Each file still holds records of one format only
+        let stamped = LogReader::new(open_log(&path).unwrap())
+            .map(|entry| entry.unwrap().payload)
+            .collect::<Vec<_>>();
+        assert_eq!(stamped, [b"hi"]);
+        let json = fs::read_to_string(&json_path).await.unwrap();
+        assert_eq!(json.lines().count(), 1);
+        assert!(json.starts_with('{') && json.contains(r#""payload":"ho""#));
+        // Switching back continues the stamped file
+        apply_reload(&config, &options, &shards).await.unwrap();
+        log(b"hu").await;
+        assert_eq!(records_in(&path), 2);
+        for path in [path, json_path] {
+            fs::remove_file(&path).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn client_affinity_keeps_a_client_on_its_shard() {
+        let config = ServerConfig {
+            shard_policy: ShardPolicy::RoundRobin,
+            client_affinity: true,
+            ..ServerConfig::from_source(&ConfigSource::default()).unwrap()
+        };
+        let path = test_path("affinity");
+        let mut shards = Vec::new();
+        for _ in 0..4 {
+            shards.push(Arc::new(Mutex::new(test_writer(&path).await)));
+        }
+        let shards: LogShards = Arc::new(shards);
+        let options = ConnectionOptions {
+            shard_loads: shards.iter().map(|_| AtomicUsize::new(0)).collect(),
+            ..connection_options(&config, &[config.log_format])
+        };
+        let busy: SocketAddr = "10.0.0.1:5000".parse().unwrap();
+        let (first, _load) = log_for(&shards, &options, &busy);
+        for i in 0..1000 {
+            let other = SocketAddr::from(([10, 1, (i / 256) as u8, i as u8], 5000));
+            drop(log_for(&shards, &options, &other));
+            let port = 5001 + i as u16;
+            let (reconnected, _) = log_for(&shards, &options, &SocketAddr::new(busy.ip(), port));
+            assert!(Arc::ptr_eq(&first, &reconnected), "moved after {i} clients");
+        }
+        fs::remove_file(&path).await.unwrap();
+    }
+
+    #[test]
+    fn binary_entries_are_never_sanitized() {
+        let config = ServerConfig {
+            sanitize: SanitizeMode::Strip,
+            ..ServerConfig::from_source(&ConfigSource::default()).unwrap()
+        };
+        let formats = [LogFormat::Stamped, LogFormat::Binary];
+        let entries = entry_options(&config, &formats);
+        let payload = b"a\x1b[0m\0b";
+        let logged = |entry| Message::new(payload, None, entry).payload.into_owned();
+        assert_eq!(logged(&entries[0]), b"a[0mb");
+        assert_eq!(logged(&entries[1]), payload);
+    }
+
+    #[tokio::test]
+    async fn run_marker_is_flushed_periodically() {
+        let path = test_path("marker");
+        let mut writer = test_writer(&path).await;
+        assert!(!writer.is_dirty());
+        writer.write_entries(b"START\n", &[], 0).await.unwrap();
+        assert!(
+            writer.is_dirty(),
+            "a marker has no receive times but needs a flush"
+        );
+        writer.flush().await.unwrap();
+        assert!(!writer.is_dirty());
+        assert_eq!(fs::read(&path).await.unwrap(), b"START\n");
+        fs::remove_file(&path).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn empty_connections_cause_no_flushes() {
+        let config = ServerConfig::from_source(&ConfigSource::default()).unwrap();
+        let options = connection_options(&config, &[config.log_format]);
+        let path = test_path("empty");
+        let file: SharedLog = Arc::new(Mutex::new(test_writer(&path).await));
+        let metrics = Arc::new(Metrics::default());
+        for sends in [&b""[..], b"", b"", b"hello", b"", b""] {
+            let (mut socket, mut client) = connected_pair().await;
+            client.write_all(sends).await.unwrap();
+            drop(client);
+            let peer = socket.peer_addr().unwrap();
+            let bytes_counter = Arc::new(Mutex::new(0));
+            log_message(
+                Arc::clone(&file),
+                &mut socket,
+                &peer,
+                bytes_counter,
+                usize::MAX,
+                Arc::clone(&metrics),
+                &options,
+            )
+            .await
+            .unwrap();
+        }
+        let writer = file.lock().await;
+        // The message waits for the periodic flush like any other
+        assert_eq!(writer.flushes, 0);
+        assert!(writer.is_dirty());
+        fs::remove_file(&path).await.unwrap();
+    }
+}
@@ -0,0 +1,112 @@
+//! Forwarding of logged payloads to other servers (`UPSTREAM_ADDRS`).
+//!
+//! Every upstream gets its own task, queue and connection, so a slow or unreachable
+//! upstream only drops its own messages and never holds up local logging or the other
+//! upstreams. Payloads are written back to back on one connection per upstream.
+//!
+//! With `UPSTREAM_COMPRESS` every connection carries a single gzip stream of those payloads,
+//! started fresh on each reconnect, so the receiving end can pipe it through `gunzip`.
+//! It's flushed whenever the queue runs empty, so payloads aren't held back.
+
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use async_compression::tokio::write::GzipEncoder;
+use bytes::Bytes;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+
+use scooper::{Metrics, UpstreamStats};
+
+const QUEUE_SIZE: usize = 1024;
+const INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+#[derive(Debug)]
+pub struct Upstream {
+    queue: mpsc::Sender<Bytes>,
+    stats: Arc<UpstreamStats>,
+}
+
+impl Upstream {
+    /// Starts the forwarding task of an upstream.
+    pub fn spawn(addr: String, compress: bool, metrics: &Metrics) -> Self {
+        let (queue, receiver) = mpsc::channel(QUEUE_SIZE);
+        let stats = metrics.register_upstream(&addr);
+        tokio::spawn(forward(addr, compress, receiver, Arc::clone(&stats)));
+        Self { queue, stats }
+    }
+
+    /// Queues a payload without waiting, it's dropped if the upstream is falling behind.
+    pub fn send(&self, payload: Bytes) {
+        if self.queue.try_send(payload).is_err() {
+            self.stats.failed.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+enum Connection {
+    Plain(TcpStream),
+    Gzip(GzipEncoder<TcpStream>),
+}
+
+impl Connection {
+    async fn write_all(&mut self, payload: &[u8], more_queued: bool) -> std::io::Result<()> {
+        match self {
+            Self::Plain(stream) => stream.write_all(payload).await,
+            Self::Gzip(encoder) => {
+                encoder.write_all(payload).await?;
+                if !more_queued {
+                    encoder.flush().await?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+async fn forward(
+    addr: String,
+    compress: bool,
+    mut queue: mpsc::Receiver<Bytes>,
+    stats: Arc<UpstreamStats>,
+) {
+    let mut stream: Option<Connection> = None;
+    let mut backoff = INITIAL_BACKOFF;
+    let mut next_attempt = Instant::now();
+    while let Some(payload) = queue.recv().await {
+        if stream.is_none() && Instant::now() >= next_attempt {
+            match TcpStream::connect(&addr).await {
+                Ok(connected) => {
+                    println!("Connected to upstream {addr}");
+                    stream = Some(match compress {
+                        true => Connection::Gzip(GzipEncoder::new(connected)),
+                        false => Connection::Plain(connected),
+                    });
+                    backoff = INITIAL_BACKOFF;
+                }
+                Err(e) => {
+                    eprintln!("Failed to connect to upstream {addr}: {e}, retrying in {backoff:?}");
+                    next_attempt = Instant::now() + backoff;
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                }
+            }
+        }
+        let Some(connected) = stream.as_mut() else {
+            stats.failed.fetch_add(1, Ordering::Relaxed);
+            continue;
+        };
+        match connected.write_all(&payload, !queue.is_empty()).await {
+            Ok(()) => {
+                stats.forwarded.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(e) => {
+                eprintln!("Lost connection to upstream {addr}: {e}");
+                stats.failed.fetch_add(1, Ordering::Relaxed);
+                stream = None;
+            }
+        }
+    }
+}